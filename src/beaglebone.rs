@@ -1,107 +1,326 @@
+use crate::error::*;
+use crate::hal::*;
 use crate::pin::*;
-use std::path::Path;
-use std::fs;
-use std::{thread, time};
+use crate::pwmchip::PwmChipRegistry;
+use crate::sysfs::Sysfs;
+use std::time;
+
+/**
+ * Order the writes of a period change keeping the duty cycle ratio, the
+ * duty cycle must never be longer than the period
+ * @param current_period_ns     period before the change
+ * @param current_duty_ns       duty cycle before the change
+ * @param period_ns             wanted period
+ * @return the duty cycle to write before the period and the one to write after
+ */
+fn period_change(current_period_ns: u32, current_duty_ns: u32, period_ns: u32) -> (Option<u32>, Option<u32>) {
+    let new_duty_ns = match current_period_ns {
+        0 => 0.0,
+        _ => (period_ns as f32 / current_period_ns as f32) * current_duty_ns as f32,
+    };
+    if period_ns < current_period_ns {
+        // Going to a shorter period, update duty_cycle first to avoid any error
+        (Some(new_duty_ns as u32), None)
+    } else if period_ns > current_period_ns {
+        // Update duty cycle to keep level
+        (None, Some(new_duty_ns as u32))
+    } else {
+        (None, None)
+    }
+}
 
 impl Pwm {
-    pub fn is_enable(&self) -> bool {
+    pub fn is_enable(&self) -> Result<bool> {
         let enabled_path = format!("/sys/class/pwm/pwm-{}:{}/enable", self.sysfs, self.index);
-        let enabled = fs::read_to_string(&enabled_path).unwrap_or(String::from("0"));
-        let enabled = enabled.trim();
-        enabled == "1"
+        Ok(self.fs.read(&enabled_path)? == "1")
     }
 
-    pub fn set_enable(&self, enable: bool) -> bool {
+    pub fn set_enable(&self, enable: bool) -> Result<()> {
         let enabled_path = format!("/sys/class/pwm/pwm-{}:{}/enable", self.sysfs, self.index);
         match enable {
-            true => fs::write(&enabled_path, String::from("1")).is_ok(),
-            _ => fs::write(&enabled_path, String::from("0")).is_ok()
+            true => self.fs.write(&enabled_path, "1"),
+            _ => self.fs.write(&enabled_path, "0")
         }
     }
 
-    pub fn get_duty_ns(&self) -> u32 {
+    fn pwm_setup(&self) -> Result<()> {
+        // For BBB SEEED, uboot is enabled (/bin/grep -c bone_capemgr.uboot_capemgr_enabled=1 /proc/cmdline)
+        // So, there is no need to initialize pwm mode.
+        // First, set the PIN in pwm mode
+        let state_path = format!("/sys/devices/platform/ocp/ocp:{}_pinmux/state", self.key);
+        self.fs.write(&state_path, "pwm")?;
+        // Second, init the PIN state
+        let pwm_path = format!("/sys/devices/platform/ocp/{}.epwmss/{}.pwm/pwm/pwmchip{}",
+            self.chip, self.addr, self.sysfs);
+        let exported_path = format!("{}/pwm-{}:{}", pwm_path, self.sysfs, self.index);
+        if !self.fs.exists(&exported_path) {
+            // Export pin
+            let export_path = format!("{}/export", pwm_path);
+            self.fs.write(&export_path, &self.index.to_string())?;
+            self.clock.sleep(time::Duration::from_millis(100));
+        }
+        Ok(())
+    }
+}
+
+impl PwmChannel for Pwm {
+    fn get_duty_ns(&self) -> Result<u32> {
         let duty_ns_path = format!("/sys/class/pwm/pwm-{}:{}/duty_cycle", self.sysfs, self.index);
-        let duty_ns = fs::read_to_string(&duty_ns_path).unwrap_or(String::from("0"));
-        let duty_ns = duty_ns.trim();
-        duty_ns.parse::<u32>().unwrap_or(0)
+        self.fs.read_u32(&duty_ns_path)
     }
 
-    pub fn get_period_ns(&self) -> u32 {
+    fn get_period_ns(&self) -> Result<u32> {
         let period_ns_path = format!("/sys/class/pwm/pwm-{}:{}/period", self.sysfs, self.index);
-        let period_ns = fs::read_to_string(&period_ns_path).unwrap_or(String::from("0"));
-        let period_ns = period_ns.trim();
-        period_ns.parse().unwrap_or(0)
+        self.fs.read_u32(&period_ns_path)
     }
 
-    pub fn set_duty_ns(&mut self, duty_ns: u32) -> bool {
+    fn set_duty_ns(&mut self, duty_ns: u32) -> Result<()> {
         let duty_ns_path = format!("/sys/class/pwm/pwm-{}:{}/duty_cycle", self.sysfs, self.index);
-        let ok = fs::write(&duty_ns_path, duty_ns.to_string()).is_ok();
-        info!("Write {} in {} - {}", duty_ns, duty_ns_path, ok);
-        ok
+        let res = self.fs.write(&duty_ns_path, &duty_ns.to_string());
+        info!("Write {} in {} - {}", duty_ns, duty_ns_path, res.is_ok());
+        res
     }
 
-    pub fn set_period_ns(&mut self, period_ns: u32) -> bool {
+    fn set_period_ns(&mut self, period_ns: u32) -> Result<()> {
         let period_ns_path = format!("/sys/class/pwm/pwm-{}:{}/period", self.sysfs, self.index);
-        let new_duty_ns = (period_ns as f32 / self.get_period_ns() as f32) * self.get_duty_ns() as f32;
-        if period_ns < self.get_duty_ns() {
-            // Going to a shorter period, update duty_cycle first to avoid any error
-            if !self.set_duty_ns(new_duty_ns as u32) {
-                error!("Can't change duty_cycle");
-                return false;
-            }
+        let current_period_ns = self.get_period_ns()?;
+        let current_duty_ns = self.get_duty_ns()?;
+        // Only claim the period once it is really used
+        PwmChipRegistry::global().check(self, period_ns)?;
+        let (duty_before, duty_after) = period_change(current_period_ns, current_duty_ns, period_ns);
+        if let Some(duty_ns) = duty_before {
+            self.set_duty_ns(duty_ns)?;
         }
         // Update freq
         info!("Write {} in {}", period_ns, period_ns_path);
-        if !fs::write(&period_ns_path, period_ns.to_string()).is_ok() {
-            error!("Can't change period {}: {}. Maybe 2 pwm uses the same pwmchip. Please see log in /var/log/syslog.", period_ns_path, period_ns);
-            return false;
+        if let Err(err) = self.fs.write(&period_ns_path, &period_ns.to_string()) {
+            error!("Can't change period {}: {}. Please see log in /var/log/syslog.", period_ns_path, period_ns);
+            return Err(err);
         }
-        if period_ns > self.get_period_ns() {
-            // Update duty cycle to keep level
-            if !self.set_duty_ns(new_duty_ns as u32) {
-                error!("Can't change duty_cycle");
-                return false;
-            }
+        PwmChipRegistry::global().claim(self, period_ns)?;
+        if let Some(duty_ns) = duty_after {
+            self.set_duty_ns(duty_ns)?;
         }
-        true
+        Ok(())
     }
 
-    fn pwm_setup(&self) -> bool {
-        // For BBB SEEED, uboot is enabled (/bin/grep -c bone_capemgr.uboot_capemgr_enabled=1 /proc/cmdline)
-        // So, there is no need to initialize pwm mode.
-        // First, set the PIN in pwm mode
-        let state_path = format!("/sys/devices/platform/ocp/ocp:{}_pinmux/state", self.key);
-        fs::write(&state_path, "pwm").expect("Unable to write file");
-        // Second, init the PIN state
-        let pwm_path = format!("/sys/devices/platform/ocp/{}.epwmss/{}.pwm/pwm/pwmchip{}",
-            self.chip, self.addr, self.sysfs);
-        let exported_path = format!("{}/pwm-{}:{}", pwm_path, self.sysfs, self.index);
-        if !Path::new(&exported_path).exists() {
-            // Export pin
-            let export_path = format!("{}/export", pwm_path);
-            fs::write(&export_path, self.index.to_string()).expect("Unable to write file");
-            thread::sleep(time::Duration::from_millis(100));
-        }
-        true
-    }
-
-    pub fn start_pwm(&mut self, duty_ns: u32, period_ns: u32) -> bool {
-        if !self.pwm_setup() {
+    fn start_pwm(&mut self, duty_ns: u32, period_ns: u32) -> Result<()> {
+        // Don't touch the pin if the pwmchip is used with another period
+        PwmChipRegistry::global().check(self, period_ns)?;
+        if let Err(err) = self.pwm_setup() {
             error!("Can't setup pwm");
-            return false;
+            return Err(err);
         }
-        if !self.set_period_ns(period_ns) {
+        if let Err(err) = self.set_period_ns(period_ns) {
             error!("Can't set period for pwm");
-            return false;
+            return Err(err);
         }
-        if !self.set_duty_ns(duty_ns) {
+        if let Err(err) = self.set_duty_ns(duty_ns) {
             error!("Can't set duty for pwm");
-            return false;
+            return Err(err);
         }
-        if !self.set_enable(true) {
+        if let Err(err) = self.set_enable(true) {
             error!("Can't enable pwm");
-            return false;
+            return Err(err);
         }
-        true
+        Ok(())
+    }
+
+    fn shared_period_ns(&self) -> Option<u32> {
+        PwmChipRegistry::global().shared_period_ns(self)
+    }
+}
+
+impl Drop for Pwm {
+    fn drop(&mut self) {
+        PwmChipRegistry::global().release(self);
+    }
+}
+
+impl GpioPin {
+    /**
+     * Export a gpio and configure it as an output
+     * @param gpio      gpio number
+     * @param fs        sysfs tree to use
+     * @return the exported pin
+     */
+    pub fn new_with_sysfs(gpio: u32, fs: Sysfs) -> Result<GpioPin> {
+        let pin = GpioPin {
+            gpio,
+            fs
+        };
+        let gpio_path = format!("/sys/class/gpio/gpio{}", pin.gpio);
+        if !pin.fs.exists(&gpio_path) {
+            match pin.fs.write("/sys/class/gpio/export", &pin.gpio.to_string()) {
+                Ok(()) => info!("Gpio {} exported!", pin.gpio),
+                Err(err) => {
+                    error!("Gpio {} could not be exported: {}", pin.gpio, err);
+                    return Err(err);
+                },
+            }
+        }
+        let direction_path = format!("{}/direction", gpio_path);
+        let direction = pin.fs.read(&direction_path).map_err(|err| {
+            error!("Gpio {} cannot get direction: {}", pin.gpio, err);
+            err
+        })?;
+        if direction != "out" {
+            pin.fs.write(&direction_path, "out")?;
+        }
+        Ok(pin)
+    }
+}
+
+impl DigitalOutput for GpioPin {
+    fn set_state(&mut self, state: State) -> Result<()> {
+        let value_path = format!("/sys/class/gpio/gpio{}/value", self.gpio);
+        self.fs.write(&value_path, &(state as u8).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::*;
+    use crate::pwmled::PwmLed;
+    use crate::servo::*;
+    use std::fs;
+    use std::sync::Arc;
+    use std::path::{Path, PathBuf};
+
+    const CHIP: &str = "sys/devices/platform/ocp/48302000.epwmss/48302200.pwm/pwm/pwmchip4";
+
+    /// Fake tree with the channels of pwmchip4, P9_14 and P9_16
+    fn fake_tree(name: &str, exported: bool) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cucaracha-pwm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (key, index) in [("P9_14", 0), ("P9_16", 1)].iter() {
+            fs::create_dir_all(root.join(format!("sys/devices/platform/ocp/ocp:{}_pinmux", key))).unwrap();
+            fs::create_dir_all(root.join(CHIP)).unwrap();
+            if exported {
+                fs::create_dir_all(root.join(CHIP).join(format!("pwm-4:{}", index))).unwrap();
+            }
+            let channel = root.join(format!("sys/class/pwm/pwm-4:{}", index));
+            fs::create_dir_all(&channel).unwrap();
+            for attribute in ["period", "duty_cycle", "enable"].iter() {
+                fs::write(channel.join(attribute), "0\n").unwrap();
+            }
+        }
+        root
+    }
+
+    fn read(root: &Path, path: &str) -> String {
+        fs::read_to_string(root.join(path)).unwrap()
+    }
+
+    #[test]
+    fn start_muxes_exports_and_enables() {
+        let root = fake_tree("start", false);
+        let clock = ManualClock::new();
+        let mut pwm = pin_to_pwm_with_clock(Gpio::P9_14.pin(), Sysfs::new(&root), Arc::new(clock.clone())).unwrap();
+        pwm.start_pwm(1500000, 20000000).unwrap();
+        assert_eq!(read(&root, "sys/devices/platform/ocp/ocp:P9_14_pinmux/state"), "pwm");
+        assert_eq!(read(&root, &format!("{}/export", CHIP)), "0");
+        // Time for udev to give access to the exported channel
+        assert_eq!(clock.now(), time::Duration::from_millis(100));
+        assert_eq!(read(&root, "sys/class/pwm/pwm-4:0/period"), "20000000");
+        assert_eq!(read(&root, "sys/class/pwm/pwm-4:0/duty_cycle"), "1500000");
+        assert!(pwm.is_enable().unwrap());
+        pwm.set_enable(false).unwrap();
+        assert!(!pwm.is_enable().unwrap());
+        drop(pwm);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn exported_channel_is_not_exported_again() {
+        let root = fake_tree("exported", true);
+        let mut pwm = pin_to_pwm_with_sysfs(Gpio::P9_14.pin(), Sysfs::new(&root)).unwrap();
+        pwm.start_pwm(1500000, 20000000).unwrap();
+        assert!(!root.join(CHIP).join("export").exists());
+        drop(pwm);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn period_change_keeps_duty_shorter_than_period() {
+        // Shorter: the duty cycle goes first, even when it already fits
+        assert_eq!(period_change(20000, 10000, 5000), (Some(2500), None));
+        assert_eq!(period_change(20000, 5000, 10000), (Some(2500), None));
+        // Longer: the period goes first
+        assert_eq!(period_change(10000, 5000, 20000), (None, Some(10000)));
+        assert_eq!(period_change(0, 0, 20000), (None, Some(0)));
+        assert_eq!(period_change(20000, 5000, 20000), (None, None));
+    }
+
+    #[test]
+    fn period_change_keeps_level() {
+        let root = fake_tree("period", true);
+        let mut pwm = pin_to_pwm_with_sysfs(Gpio::P9_14.pin(), Sysfs::new(&root)).unwrap();
+        pwm.start_pwm(5000, 20000).unwrap();
+        pwm.set_period_ns(10000).unwrap();
+        assert_eq!((pwm.get_period_ns().unwrap(), pwm.get_duty_ns().unwrap()), (10000, 2500));
+        pwm.set_period_ns(40000).unwrap();
+        assert_eq!((pwm.get_period_ns().unwrap(), pwm.get_duty_ns().unwrap()), (40000, 10000));
+        drop(pwm);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn conflicting_pin_is_left_alone() {
+        let root = fake_tree("conflict", false);
+        let clock: Arc<dyn Clock> = Arc::new(ManualClock::new());
+        let mut first = pin_to_pwm_with_clock(Gpio::P9_14.pin(), Sysfs::new(&root), clock).unwrap();
+        first.start_pwm(1500000, 20000000).unwrap();
+        let mut second = pin_to_pwm_with_sysfs(Gpio::P9_16.pin(), Sysfs::new(&root)).unwrap();
+        assert!(matches!(second.start_pwm(500, 1000), Err(Error::PwmPeriodConflict { .. })));
+        assert!(!root.join("sys/devices/platform/ocp/ocp:P9_16_pinmux/state").exists());
+        assert_eq!(read(&root, &format!("{}/export", CHIP)), "0");
+        assert_eq!(second.shared_period_ns(), Some(20000000));
+        drop((first, second));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rejected_duplicate_keeps_the_owner_claim() {
+        let root = fake_tree("duplicate", true);
+        let mut owner = pin_to_pwm_with_sysfs(Gpio::P9_14.pin(), Sysfs::new(&root)).unwrap();
+        owner.start_pwm(1500000, 20000000).unwrap();
+        let mut other = pin_to_pwm_with_sysfs(Gpio::P9_16.pin(), Sysfs::new(&root)).unwrap();
+        other.start_pwm(500000, 20000000).unwrap();
+        let mut duplicate = pin_to_pwm_with_sysfs(Gpio::P9_14.pin(), Sysfs::new(&root)).unwrap();
+        assert!(matches!(duplicate.start_pwm(500, 1000), Err(Error::PwmPeriodConflict { .. })));
+        drop(duplicate);
+        // P9_14 still holds the period
+        assert_eq!(other.shared_period_ns(), Some(20000000));
+        assert!(matches!(other.set_period_ns(1000), Err(Error::PwmPeriodConflict { .. })));
+        drop((owner, other));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn drivers_wait_on_their_clock() {
+        let root = fake_tree("clock", false);
+        let clock = ManualClock::new();
+        let mut led = PwmLed::new_with_clock(Gpio::P9_14.pin(), 1.0, Sysfs::new(&root), Arc::new(clock.clone())).unwrap();
+        assert_eq!(clock.now(), time::Duration::from_millis(100));
+        led.fade_to(0.0, 1000, 100).unwrap();
+        assert_eq!(clock.now(), time::Duration::from_millis(1100));
+        assert_eq!(led.get_luminosity().unwrap(), 0.0);
+
+        let mut servo = Servo::new_with_clock(Gpio::P9_16.pin(), ServoProfile::SG90, 0.0, Sysfs::new(&root),
+            Arc::new(clock.clone()));
+        // The LED imposes its period on the chip
+        assert!(matches!(servo, Err(Error::PwmPeriodConflict { .. })));
+        drop(led);
+        servo = Servo::new_with_clock(Gpio::P9_16.pin(), ServoProfile::SG90, 0.0, Sysfs::new(&root),
+            Arc::new(clock.clone()));
+        let mut servo = servo.unwrap();
+        assert_eq!(clock.now(), time::Duration::from_millis(1200));
+        servo.go_to(90.0, 500, 100).unwrap();
+        assert_eq!(clock.now(), time::Duration::from_millis(1700));
+        assert_eq!(read(&root, "sys/class/pwm/pwm-4:1/duty_cycle"), "1450000");
+        drop(servo);
+        fs::remove_dir_all(&root).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,150 @@
+extern crate cucaracha;
+extern crate env_logger;
+extern crate libc;
+#[macro_use]
+extern crate log;
+
+use cucaracha::emulator::{MaestroChain, MaestroEmulator};
+use std::ffi::CStr;
+use std::io::prelude::*;
+use std::os::unix::fs::symlink;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::time::Instant;
+use std::{env, fs, io, process};
+
+/**
+ * Simulate a Maestro on a pseudo-terminal, so Maestro::new_with_settings
+ * (or maestro/maestro.py) can open it like /dev/ttyACM0.
+ * Usage: maestro-sim [--device 12] [--channels 24] [--link /tmp/ttyMaestro]
+ * Repeat --device to daisy-chain several boards on the same line.
+ */
+struct Options {
+    devices: Vec<u8>,
+    channels: usize,
+    link: Option<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: maestro-sim [--device <0-127>] [--channels <count>] [--link <path>]");
+    process::exit(1);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        devices: Vec::new(),
+        channels: 24,
+        link: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            // Device numbers are 7 bits, like the bytes of the Pololu protocol
+            "--device" => options.devices.push(value.parse().ok()
+                .filter(|&device: &u8| device < 128)
+                .unwrap_or_else(|| usage())),
+            "--channels" => options.channels = value.parse().unwrap_or_else(|_| usage()),
+            "--link" => options.link = Some(value),
+            _ => usage(),
+        }
+    }
+    if options.devices.is_empty() {
+        options.devices.push(0x0c);
+    }
+    options
+}
+
+fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+    match res {
+        -1 => Err(io::Error::last_os_error()),
+        res => Ok(res),
+    }
+}
+
+/**
+ * Open a new pseudo-terminal
+ * @return the master fd and the path of the slave
+ */
+fn open_pty() -> io::Result<(RawFd, String)> {
+    unsafe {
+        let master = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK))?;
+        check(libc::grantpt(master))?;
+        check(libc::unlockpt(master))?;
+        let name = libc::ptsname(master);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((master, CStr::from_ptr(name).to_string_lossy().into_owned()))
+    }
+}
+
+/**
+ * Put the slave in raw mode and keep it open, so the master doesn't see
+ * EIO between two clients and nothing is echoed before a client configures it
+ * @param path      path of the slave
+ * @return the slave fd
+ */
+fn hold_slave(path: &str) -> io::Result<RawFd> {
+    let c_path = std::ffi::CString::new(path).unwrap();
+    unsafe {
+        let slave = check(libc::open(c_path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY))?;
+        let mut termios: libc::termios = std::mem::zeroed();
+        check(libc::tcgetattr(slave, &mut termios))?;
+        libc::cfmakeraw(&mut termios);
+        check(libc::tcsetattr(slave, libc::TCSANOW, &termios))?;
+        Ok(slave)
+    }
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let (master, path) = open_pty()?;
+    let _slave = hold_slave(&path)?;
+    let mut pty = unsafe { fs::File::from_raw_fd(master) };
+    if let Some(link) = options.link.as_ref() {
+        let _ = fs::remove_file(link);
+        symlink(&path, link)?;
+    }
+    println!("{}", options.link.as_ref().unwrap_or(&path));
+
+    let mut emulator = MaestroChain::new(options.devices.iter()
+        .map(|&device| MaestroEmulator::new(device, options.channels))
+        .collect());
+    let mut last = Instant::now();
+    let mut buf = [0; 256];
+    loop {
+        let mut fds = libc::pollfd { fd: master, events: libc::POLLIN, revents: 0 };
+        check(unsafe { libc::poll(&mut fds, 1, 10) })?;
+
+        // Servos move with the real time
+        let now = Instant::now();
+        emulator.advance(now - last);
+        last = now;
+
+        match pty.read(&mut buf) {
+            Ok(len) => {
+                debug!("Received {:x?}", &buf[..len]);
+                emulator.write_all(&buf[..len])?;
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        }
+        let mut answer = [0; 64];
+        while let Ok(len) = emulator.read(&mut answer) {
+            debug!("Answer {:x?}", &answer[..len]);
+            pty.write_all(&answer[..len])?;
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let options = parse_options();
+    let link = options.link.clone();
+    if let Err(err) = run(options) {
+        error!("Maestro simulator stopped: {}", err);
+        if let Some(link) = link {
+            let _ = fs::remove_file(link);
+        }
+        process::exit(1);
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/**
+ * Source of time used by the drivers, so timings can be checked without
+ * waiting in tests
+ */
+pub trait Clock: Send + Sync {
+    /**
+     * @return the time elapsed since the creation of the clock
+     */
+    fn now(&self) -> Duration;
+
+    /**
+     * Block for a given time
+     * @param duration      time to wait
+     */
+    fn sleep(&self, duration: Duration);
+
+    /**
+     * Block until a given time, return immediately if it is already passed
+     * @param deadline      time to reach, as returned by now()
+     */
+    fn sleep_until(&self, deadline: Duration) {
+        let now = self.now();
+        if deadline > now {
+            self.sleep(deadline - now);
+        }
+    }
+}
+
+/**
+ * The real time, sleeping with std::thread::sleep
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock {
+            start: Instant::now()
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/**
+ * @return the clock shared by the drivers by default
+ */
+pub fn system_clock() -> Arc<dyn Clock> {
+    static CLOCK: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+    CLOCK.get_or_init(|| Arc::new(SystemClock::default())).clone()
+}
+
+/**
+ * A virtual time which only moves when someone sleeps or calls advance,
+ * so a 5 s transition runs instantly. Clones share the same time.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    /**
+     * Get a new clock at 0
+     * @return the new clock
+     */
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    /**
+     * Let time pass
+     * @param duration      time to add
+     */
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,621 @@
+use crate::error::Result;
+use crate::maestro::{crc7, MaestroErrors, MaestroPort, ScriptStatus};
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The Maestro updates its outputs every 10 ms
+const TICK: Duration = Duration::from_millis(10);
+/// Mini SSC values 0-254 are mapped between 1000 µs and 2000 µs
+const MINI_SSC_MIN: u16 = 4000;
+const MINI_SSC_MAX: u16 = 8000;
+
+/**
+ * State of one emulated channel, positions are in quarter-µs
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmulatedChannel {
+    pub position: f32,
+    pub target: u16,
+    pub speed: u16,
+    pub accel: u16,
+    pub home: u16,
+    velocity: f32,
+}
+
+impl EmulatedChannel {
+    fn new(home: u16) -> EmulatedChannel {
+        EmulatedChannel {
+            position: home as f32,
+            target: home,
+            speed: 0,
+            accel: 0,
+            home,
+            velocity: 0.0,
+        }
+    }
+
+    fn is_moving(&self) -> bool {
+        self.position.round() as u16 != self.target
+    }
+
+    /**
+     * Move the channel by one 10 ms frame.
+     * speed is in (0.25 µs)/(10 ms), accel in (0.25 µs)/(10 ms)/(80 ms)
+     */
+    fn tick(&mut self) {
+        let distance = self.target as f32 - self.position;
+        if distance == 0.0 {
+            self.velocity = 0.0;
+            return;
+        }
+        let mut velocity = match self.speed {
+            0 => f32::INFINITY,
+            speed => speed as f32,
+        };
+        if self.accel > 0 {
+            let accel = self.accel as f32 / 8.0;
+            // Accelerate, but keep enough room to stop on the target
+            velocity = velocity
+                .min(self.velocity + accel)
+                .min((2.0 * accel * distance.abs()).sqrt().max(accel));
+        }
+        let step = velocity.min(distance.abs());
+        self.position += step * distance.signum();
+        self.velocity = if step == distance.abs() { 0.0 } else { step };
+    }
+}
+
+/**
+ * Everything the emulated board knows
+ */
+#[derive(Debug)]
+struct EmulatorState {
+    device: u8,
+    channels: Vec<EmulatedChannel>,
+    errors: u16,
+    script: ScriptStatus,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    elapsed: Duration,
+    pending: Duration,
+    auto_advance: Duration,
+    timeout: Duration,
+    unresponsive: bool,
+    crc: bool,
+}
+
+/**
+ * Result of parsing the start of the input buffer
+ */
+enum Parsed {
+    /// Need more bytes
+    Incomplete,
+    /// Bytes to drop, not for us
+    Skip(usize),
+    /// Bytes to drop, unknown command
+    Invalid(usize),
+    /// Bytes to drop, wrong CRC
+    CrcError(usize),
+    /// A complete command: bytes consumed, command byte (MSB set), data
+    Command(usize, u8, Vec<u8>),
+}
+
+/**
+ * @param command       command byte with its MSB set
+ * @param data          bytes received after the command byte
+ * @return the number of data bytes of the command, None if unknown
+ */
+fn data_len(command: u8, data: &[u8]) -> Option<usize> {
+    match command {
+        0x84 | 0x87 | 0x89 | 0xa8 => Some(3),
+        0x90 | 0xa7 => Some(1),
+        0x93 | 0xa1 | 0xa2 | 0xa4 | 0xae => Some(0),
+        // Mini SSC: channel, value
+        0xff => Some(2),
+        // count, first channel, then 2 bytes per target
+        0x9f => Some(2 + 2 * *data.first().unwrap_or(&0x7f) as usize),
+        _ => None,
+    }
+}
+
+impl EmulatorState {
+    fn parse(&self) -> Parsed {
+        let buf = &self.input;
+        let (header, device) = match buf.first() {
+            None => return Parsed::Incomplete,
+            Some(0xaa) => match buf.get(1) {
+                None => return Parsed::Incomplete,
+                // A lone 0xaa, like the baud rate detection byte
+                Some(&device) if device & 0x80 != 0 => return Parsed::Skip(1),
+                Some(&device) => (2, Some(device)),
+            },
+            Some(_) => (0, None),
+        };
+        let command = match buf.get(header) {
+            None => return Parsed::Incomplete,
+            // Pololu protocol sends the command with its MSB cleared
+            Some(&command) if device.is_some() => command | 0x80,
+            Some(&command) => command,
+        };
+        let data = &buf[header + 1..];
+        let len = match data_len(command, data) {
+            Some(len) => len,
+            None => return Parsed::Invalid(header + 1),
+        };
+        let crc_len = (self.crc && command != 0xff) as usize;
+        if data.len() < len + crc_len {
+            return Parsed::Incomplete;
+        }
+        let consumed = header + 1 + len + crc_len;
+        if crc_len == 1 && crc7(&buf[..consumed - 1]) != buf[consumed - 1] {
+            return Parsed::CrcError(consumed);
+        }
+        match device {
+            Some(device) if device != self.device => Parsed::Skip(consumed),
+            _ => Parsed::Command(consumed, command, data[..len].to_vec()),
+        }
+    }
+
+    fn process_input(&mut self) {
+        loop {
+            match self.parse() {
+                Parsed::Incomplete => break,
+                Parsed::Skip(len) => {
+                    self.input.drain(..len);
+                },
+                Parsed::Invalid(len) => {
+                    warn!("Maestro emulator: unknown command {:#x}", self.input[len - 1]);
+                    self.errors |= MaestroErrors::SERIAL_PROTOCOL.0;
+                    self.input.drain(..len);
+                },
+                Parsed::CrcError(len) => {
+                    warn!("Maestro emulator: wrong CRC for {:x?}", &self.input[..len]);
+                    self.errors |= MaestroErrors::SERIAL_CRC.0;
+                    self.input.drain(..len);
+                },
+                Parsed::Command(len, command, data) => {
+                    self.input.drain(..len);
+                    self.execute(command, &data);
+                },
+            }
+        }
+    }
+
+    fn channel(&mut self, channel: u8) -> Option<&mut EmulatedChannel> {
+        let res = self.channels.get_mut(channel as usize);
+        if res.is_none() {
+            self.errors |= MaestroErrors::SERIAL_PROTOCOL.0;
+        }
+        res
+    }
+
+    fn execute(&mut self, command: u8, data: &[u8]) {
+        if command == 0xff {
+            let range = (MINI_SSC_MAX - MINI_SSC_MIN) as u32;
+            let target = MINI_SSC_MIN + (range * data[1].min(254) as u32 / 254) as u16;
+            if let Some(channel) = self.channel(data[0]) {
+                channel.target = target;
+            }
+            return;
+        }
+        if data.iter().any(|byte| byte & 0x80 != 0) {
+            self.errors |= MaestroErrors::SERIAL_PROTOCOL.0;
+            return;
+        }
+        let value = |lsb: u8, msb: u8| (lsb as u16) | ((msb as u16) << 7);
+        match command {
+            0x84 => if let Some(channel) = self.channel(data[0]) {
+                channel.target = value(data[1], data[2]);
+            },
+            0x87 => if let Some(channel) = self.channel(data[0]) {
+                channel.speed = value(data[1], data[2]);
+            },
+            0x89 => if let Some(channel) = self.channel(data[0]) {
+                channel.accel = value(data[1], data[2]);
+            },
+            0x90 => {
+                let position = self.channel(data[0]).map(|c| c.position.round() as u16).unwrap_or(0);
+                self.output.extend(&[(position & 0xff) as u8, (position >> 8) as u8]);
+            },
+            0x93 => {
+                let moving = self.channels.iter().any(|c| c.is_moving());
+                self.output.push_back(moving as u8);
+            },
+            0x9f => {
+                let (count, first) = (data[0], data[1]);
+                for i in 0..count {
+                    let target = value(data[2 + 2 * i as usize], data[3 + 2 * i as usize]);
+                    if let Some(channel) = self.channel(first.saturating_add(i)) {
+                        channel.target = target;
+                    }
+                }
+            },
+            0xa1 => {
+                let errors = self.errors;
+                self.errors = 0;
+                self.output.extend(&[(errors & 0xff) as u8, (errors >> 8) as u8]);
+            },
+            0xa2 => for channel in self.channels.iter_mut() {
+                channel.target = channel.home;
+            },
+            0xa4 => self.script = ScriptStatus::Stopped,
+            0xa7 | 0xa8 => self.script = ScriptStatus::Running,
+            0xae => {
+                let status = match self.script {
+                    ScriptStatus::Running => 0,
+                    ScriptStatus::Stopped => 1,
+                };
+                self.output.push_back(status);
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn advance(&mut self, duration: Duration) {
+        self.elapsed += duration;
+        self.pending += duration;
+        while self.pending >= TICK {
+            self.pending -= TICK;
+            for channel in self.channels.iter_mut() {
+                channel.tick();
+            }
+        }
+    }
+}
+
+/**
+ * In-process emulation of a Maestro board, usable as a MaestroPort. It
+ * understands the Pololu, compact and Mini SSC protocols.
+ * Time only moves when advance() is called (or by auto_advance on each
+ * command), so tests are deterministic. Clones share the same board.
+ */
+#[derive(Debug, Clone)]
+pub struct MaestroEmulator {
+    state: Arc<Mutex<EmulatorState>>,
+}
+
+impl MaestroEmulator {
+    /**
+     * Get a new emulated board with all channels at 1500 µs
+     * @param device        device number for the Pololu protocol
+     * @param channels      number of channels (6, 12, 18 or 24)
+     * @return the new emulator
+     */
+    pub fn new(device: u8, channels: usize) -> MaestroEmulator {
+        MaestroEmulator {
+            state: Arc::new(Mutex::new(EmulatorState {
+                device,
+                channels: vec![EmulatedChannel::new(6000); channels],
+                errors: 0,
+                script: ScriptStatus::Stopped,
+                input: Vec::new(),
+                output: VecDeque::new(),
+                elapsed: Duration::from_secs(0),
+                pending: Duration::from_secs(0),
+                auto_advance: Duration::from_secs(0),
+                timeout: Duration::from_secs(0),
+                unresponsive: false,
+                crc: false,
+            }))
+        }
+    }
+
+    /**
+     * Let time pass on the board, servos move by 10 ms frames
+     * @param duration      time to add
+     */
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().advance(duration);
+    }
+
+    /**
+     * Let time pass by a fixed amount after each write on the port, so a
+     * loop polling the board eventually sees servos reach their targets
+     * @param duration      time added per write
+     */
+    pub fn set_auto_advance(&self, duration: Duration) {
+        self.state.lock().unwrap().auto_advance = duration;
+    }
+
+    /**
+     * @return the emulated time since the creation of the board
+     */
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    /**
+     * @param channel       channel to read
+     * @return a copy of the channel state
+     */
+    pub fn channel(&self, channel: u8) -> Option<EmulatedChannel> {
+        self.state.lock().unwrap().channels.get(channel as usize).cloned()
+    }
+
+    /**
+     * Change the position used by Go Home
+     * @param channel       channel to configure
+     * @param home          home target in quarter-µs
+     */
+    pub fn set_home(&self, channel: u8, home: u16) {
+        if let Some(c) = self.state.lock().unwrap().channels.get_mut(channel as usize) {
+            c.home = home;
+        }
+    }
+
+    /**
+     * Require a CRC-7 byte at the end of each command
+     * @param crc           if the CRC is required
+     */
+    pub fn set_crc(&self, crc: bool) {
+        self.state.lock().unwrap().crc = crc;
+    }
+
+    /**
+     * Send bytes nobody asked for, like noise on the line
+     * @param bytes         bytes to add before the next answer
+     */
+    pub fn inject_output(&self, bytes: &[u8]) {
+        self.state.lock().unwrap().output.extend(bytes);
+    }
+
+    /**
+     * Simulate an unplugged board: commands are dropped and nothing is answered
+     * @param unresponsive  if the board ignores commands
+     */
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.state.lock().unwrap().unresponsive = unresponsive;
+    }
+
+    /**
+     * Raise errors, as if the board detected them
+     * @param errors        errors to add
+     */
+    pub fn raise_errors(&self, errors: MaestroErrors) {
+        self.state.lock().unwrap().errors |= errors.0;
+    }
+}
+
+impl Read for MaestroEmulator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.output.is_empty() && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no answer from the Maestro"));
+        }
+        let len = buf.len().min(state.output.len());
+        for (byte, value) in buf.iter_mut().zip(state.output.drain(..len)) {
+            *byte = value;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MaestroEmulator {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.unresponsive {
+            return Ok(buf.len());
+        }
+        state.input.extend_from_slice(buf);
+        state.process_input();
+        let auto_advance = state.auto_advance;
+        state.advance(auto_advance);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MaestroPort for MaestroEmulator {
+    /**
+     * Answers are available as soon as the command is written, so the
+     * timeout is only stored
+     */
+    fn timeout(&self) -> Duration {
+        self.state.lock().unwrap().timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.state.lock().unwrap().timeout = timeout;
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        self.state.lock().unwrap().output.clear();
+        Ok(())
+    }
+}
+
+/**
+ * Several emulated boards daisy-chained on one serial line: every board
+ * receives each command, and their answers share the same line
+ */
+#[derive(Debug, Clone)]
+pub struct MaestroChain {
+    boards: Vec<MaestroEmulator>,
+    timeout: Duration,
+}
+
+impl MaestroChain {
+    /**
+     * Connect boards on the same line
+     * @param boards        boards to chain, with different device numbers
+     * @return the new chain
+     */
+    pub fn new(boards: Vec<MaestroEmulator>) -> MaestroChain {
+        MaestroChain {
+            boards,
+            timeout: Duration::from_secs(0),
+        }
+    }
+
+    /**
+     * @return the chained boards
+     */
+    pub fn boards(&self) -> &[MaestroEmulator] {
+        &self.boards
+    }
+
+    /**
+     * Let time pass on every board
+     * @param duration      time to add
+     */
+    pub fn advance(&self, duration: Duration) {
+        for board in self.boards.iter() {
+            board.advance(duration);
+        }
+    }
+}
+
+impl Read for MaestroChain {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for board in self.boards.iter_mut() {
+            match board.read(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => continue,
+                res => return res,
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no answer from the Maestros"))
+    }
+}
+
+impl Write for MaestroChain {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for board in self.boards.iter_mut() {
+            board.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl MaestroPort for MaestroChain {
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<()> {
+        for board in self.boards.iter_mut() {
+            board.clear_input()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maestro::{Maestro, MaestroSettings};
+    use crate::servo::Servo;
+
+    fn answer(emulator: &mut MaestroEmulator, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        emulator.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn both_protocols_are_understood() {
+        let mut emulator = MaestroEmulator::new(12, 6);
+        // Pololu, for this board then for another one
+        emulator.write_all(&[0xaa, 12, 0x04, 1, 0x70, 0x2e]).unwrap();
+        emulator.write_all(&[0xaa, 13, 0x04, 2, 0x70, 0x2e]).unwrap();
+        // Compact, split across two writes
+        emulator.write_all(&[0x84, 3, 0x40]).unwrap();
+        emulator.write_all(&[0x3e]).unwrap();
+        assert_eq!(emulator.channel(1).unwrap().target, 6000);
+        assert_eq!(emulator.channel(2).unwrap().target, 6000);
+        assert_eq!(emulator.channel(3).unwrap().target, 8000);
+
+        // Time didn't pass, the position is still the start one
+        emulator.write_all(&[0xaa, 12, 0x10, 3]).unwrap();
+        assert_eq!(answer(&mut emulator, 2), [0x70, 0x17]);
+        emulator.write_all(&[0x9f, 2, 4, 0x20, 0x1f, 0x40, 0x3e]).unwrap();
+        assert_eq!((emulator.channel(4).unwrap().target, emulator.channel(5).unwrap().target), (4000, 8000));
+
+        emulator.set_crc(true);
+        let mut packet = vec![0xaa, 12, 0x04, 0, 0x20, 0x1f];
+        packet.push(crc7(&packet));
+        emulator.write_all(&packet).unwrap();
+        assert_eq!(emulator.channel(0).unwrap().target, 4000);
+        emulator.write_all(&[0x84, 0, 0x70, 0x2e, 0]).unwrap();
+        assert_eq!(emulator.channel(0).unwrap().target, 4000);
+        emulator.write_all(&[0xa1, crc7(&[0xa1])]).unwrap();
+        assert_eq!(answer(&mut emulator, 2), [MaestroErrors::SERIAL_CRC.0 as u8, 0]);
+    }
+
+    #[test]
+    fn channels_ramp_with_speed_and_accel() {
+        let mut emulator = MaestroEmulator::new(12, 6);
+        emulator.write_all(&[0x87, 0, 10, 0]).unwrap();
+        emulator.write_all(&[0x84, 0, 0x58, 0x36]).unwrap();
+        emulator.advance(Duration::from_millis(100));
+        assert_eq!(emulator.channel(0).unwrap().position, 6100.0);
+
+        // 1 quarter-µs per frame more at each frame
+        emulator.write_all(&[0x89, 1, 8, 0]).unwrap();
+        emulator.write_all(&[0x84, 1, 0x58, 0x36]).unwrap();
+        emulator.advance(Duration::from_millis(50));
+        assert_eq!(emulator.channel(1).unwrap().position, 6015.0);
+
+        // Without limits, the target is reached in one frame
+        emulator.write_all(&[0x84, 2, 0x58, 0x36]).unwrap();
+        emulator.advance(Duration::from_millis(10));
+        assert_eq!(emulator.channel(2).unwrap().position, 7000.0);
+
+        emulator.write_all(&[0x93]).unwrap();
+        assert_eq!(answer(&mut emulator, 1), [1]);
+        emulator.advance(Duration::from_secs(2));
+        emulator.write_all(&[0x93]).unwrap();
+        assert_eq!(answer(&mut emulator, 1), [0]);
+        assert_eq!(emulator.elapsed(), Duration::from_millis(2160));
+    }
+
+    #[test]
+    fn bad_commands_raise_protocol_errors() {
+        let mut emulator = MaestroEmulator::new(12, 6);
+        emulator.write_all(&[0x84, 6, 0x70, 0x2e]).unwrap();
+        emulator.write_all(&[0xa1]).unwrap();
+        assert_eq!(answer(&mut emulator, 2), [MaestroErrors::SERIAL_PROTOCOL.0 as u8, 0]);
+        // Reading the errors clears them
+        emulator.write_all(&[0xa1]).unwrap();
+        assert_eq!(answer(&mut emulator, 2), [0, 0]);
+
+        emulator.write_all(&[0x80]).unwrap();
+        emulator.write_all(&[0xa1]).unwrap();
+        assert_eq!(answer(&mut emulator, 2), [MaestroErrors::SERIAL_PROTOCOL.0 as u8, 0]);
+        let mut buf = [0; 1];
+        assert_eq!(emulator.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn servo_moves_on_the_emulator() {
+        let emulator = MaestroEmulator::new(12, 6);
+        let maestro = Maestro::new_with_port(Box::new(emulator.clone()), MaestroSettings::default()).unwrap();
+        let maestro = Arc::new(Mutex::new(maestro));
+        maestro.lock().unwrap().set_speed(0, 100).unwrap();
+        let mut servo = Servo::new_from_maestro(180.0, 0, maestro.clone());
+        servo.set_position(180.0).unwrap();
+        let target = emulator.channel(0).unwrap().target;
+        assert_eq!(target, maestro.lock().unwrap().max_target);
+        assert!(maestro.lock().unwrap().is_moving().unwrap());
+
+        emulator.advance(Duration::from_secs(1));
+        assert!(!maestro.lock().unwrap().is_moving().unwrap());
+        assert!((servo.position().unwrap() - 180.0).abs() < 0.1);
+        assert_eq!(servo.quarter_us().unwrap(), target);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{fmt, io};
+
+/**
+ * Every error a driver of this crate can return
+ */
+#[derive(Debug)]
+pub enum Error {
+    /// A sysfs attribute could not be read or written
+    Sysfs { path: String, source: io::Error },
+    /// The serial link to a board failed
+    Serial(serial::Error),
+    /// The pin has no PWM function
+    InvalidPwmPin(String),
+    /// The pin has no GPIO function
+    InvalidGpioPin(String),
+    /// Another channel of the same pwmchip already uses another period
+    PwmPeriodConflict { pin: String, owner: String, period_ns: u32, requested_ns: u32 },
+    /// Servos can't be moved together
+    InvalidServoGroup(String),
+    /// The operation is not available with the current configuration
+    Unsupported(String),
+    /// A board answered something unexpected
+    Protocol(String),
+    /// A board did not answer in time
+    Timeout,
+    /// No board was found
+    NotFound(String),
+    /// A foot position is out of reach of its leg
+    Unreachable(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /**
+     * Wrap an io::Error raised while accessing a sysfs attribute
+     * @param path      Accessed path
+     * @param source    Error raised
+     * @return the new Error
+     */
+    pub fn sysfs<P: Into<String>>(path: P, source: io::Error) -> Error {
+        Error::Sysfs { path: path.into(), source }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Sysfs { path, source } => write!(f, "sysfs error on {}: {}", path, source),
+            Error::Serial(err) => write!(f, "serial error: {}", err),
+            Error::InvalidPwmPin(pin) => write!(f, "{} can't be used as a PWM", pin),
+            Error::InvalidGpioPin(pin) => write!(f, "{} can't be used as a GPIO", pin),
+            Error::PwmPeriodConflict { pin, owner, period_ns, requested_ns } =>
+                write!(f, "{} can't use a period of {} ns, {} shares the pwmchip with a period of {} ns",
+                    pin, requested_ns, owner, period_ns),
+            Error::InvalidServoGroup(msg) => write!(f, "invalid servo group: {}", msg),
+            Error::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+            Error::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Error::Timeout => write!(f, "timeout while waiting for an answer"),
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::Unreachable(msg) => write!(f, "unreachable: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Sysfs { source, .. } => Some(source),
+            Error::Serial(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<serial::Error> for Error {
+    fn from(err: serial::Error) -> Error {
+        Error::Serial(err)
+    }
+}
@@ -0,0 +1,340 @@
+use crate::clock::*;
+use crate::error::*;
+use crate::kinematics::*;
+use crate::motion::{run_transition, Easing};
+use std::f32::consts::PI;
+use std::time::Duration;
+
+/**
+ * Order in which the legs are lifted. Legs are expected in the order left
+ * front, left middle, left rear, right front, right middle, right rear.
+ * Tripod: three legs at a time, fast but less stable
+ * Wave: one leg at a time, slow and stable
+ * Ripple: two legs at a time, one per side
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GaitKind {
+    Tripod,
+    Wave,
+    Ripple,
+}
+
+impl GaitKind {
+    /**
+     * @return when each leg lifts, as a ratio of the cycle
+     */
+    pub fn phases(&self) -> [f32; 6] {
+        match *self {
+            GaitKind::Tripod => [0.0, 0.5, 0.0, 0.5, 0.0, 0.5],
+            GaitKind::Wave => [5.0 / 6.0, 4.0 / 6.0, 3.0 / 6.0, 2.0 / 6.0, 1.0 / 6.0, 0.0],
+            GaitKind::Ripple => [2.0 / 3.0, 1.0 / 3.0, 0.0, 1.0 / 6.0, 5.0 / 6.0, 0.5],
+        }
+    }
+
+    /**
+     * @return the ratio of the cycle a leg spends in the air
+     */
+    pub fn swing_ratio(&self) -> f32 {
+        match *self {
+            GaitKind::Tripod => 0.5,
+            GaitKind::Wave => 1.0 / 6.0,
+            GaitKind::Ripple => 1.0 / 3.0,
+        }
+    }
+}
+
+/**
+ * Settings of a gait
+ * step_height: how high a foot is lifted in mm
+ * stride_length: longest move of a foot during a cycle in mm, faster
+ * commands are slowed down
+ * cycle_time: time for every leg to make one step
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GaitSettings {
+    pub kind: GaitKind,
+    pub step_height: f32,
+    pub stride_length: f32,
+    pub cycle_time: Duration,
+}
+
+impl Default for GaitSettings {
+    fn default() -> GaitSettings {
+        GaitSettings {
+            kind: GaitKind::Tripod,
+            step_height: 30.0,
+            stride_length: 60.0,
+            cycle_time: Duration::from_secs(1),
+        }
+    }
+}
+
+/**
+ * Wanted move of the body
+ * x/y: speed in mm/s, x forward and y to the left
+ * turn_rate: rotation speed in degrees/s, counterclockwise seen from above
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Velocity {
+    pub x: f32,
+    pub y: f32,
+    pub turn_rate: f32,
+}
+
+/**
+ * Generate the foot positions of a walking robot
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gait {
+    pub settings: GaitSettings,
+    neutral: Vec<Point3>,
+    phase: f32,
+}
+
+impl Gait {
+    /**
+     * Create a new gait
+     * @param settings      settings of the gait
+     * @param neutral       foot positions when standing, the center of each step
+     * @return the new gait, Error::Unsupported if there are not 6 legs
+     */
+    pub fn new(settings: GaitSettings, neutral: Vec<Point3>) -> Result<Gait> {
+        if neutral.len() != 6 {
+            return Err(Error::Unsupported(format!("gaits need 6 legs, not {}", neutral.len())));
+        }
+        Ok(Gait {
+            settings,
+            neutral,
+            phase: 0.0,
+        })
+    }
+
+    /**
+     * @return the position in the cycle, between 0 and 1
+     */
+    pub fn phase(&self) -> f32 {
+        self.phase
+    }
+
+    /**
+     * Foot position along its stride
+     * @param neutral       center of the stride
+     * @param velocity      wanted move
+     * @param stance        time spent on the ground during a cycle, in s
+     * @param progress      -0.5 at the back of the stride, 0.5 at the front
+     * @return the foot position
+     */
+    fn stride_point(neutral: &Point3, velocity: &Velocity, stance: f32, progress: f32) -> Point3 {
+        // The front of the stride is where the body goes, so the foot moves
+        // back while it pushes
+        let (sin, cos) = (velocity.turn_rate.to_radians() * stance * progress).sin_cos();
+        Point3 {
+            x: cos * neutral.x - sin * neutral.y + velocity.x * stance * progress,
+            y: sin * neutral.x + cos * neutral.y + velocity.y * stance * progress,
+            z: neutral.z,
+        }
+    }
+
+    /**
+     * @param velocity      wanted move
+     * @return the move, slowed down so every stride fits in stride_length
+     */
+    fn limit(&self, velocity: &Velocity) -> Velocity {
+        let stance = self.settings.cycle_time.as_secs_f32() * (1.0 - self.settings.kind.swing_ratio());
+        let longest = self.neutral.iter()
+            .map(|neutral| Gait::stride_point(neutral, velocity, stance, -0.5)
+                .distance(&Gait::stride_point(neutral, velocity, stance, 0.5)))
+            .fold(0.0, f32::max);
+        let ratio = match longest > self.settings.stride_length {
+            true => self.settings.stride_length / longest,
+            false => 1.0,
+        };
+        Velocity {
+            x: velocity.x * ratio,
+            y: velocity.y * ratio,
+            turn_rate: velocity.turn_rate * ratio,
+        }
+    }
+
+    /**
+     * @param velocity      wanted move
+     * @return the foot positions at the current phase, in the order of the legs
+     */
+    pub fn feet(&self, velocity: &Velocity) -> Vec<Point3> {
+        let velocity = self.limit(velocity);
+        let swing = self.settings.kind.swing_ratio();
+        let stance = self.settings.cycle_time.as_secs_f32() * (1.0 - swing);
+        let moving = velocity != Velocity::default();
+        self.neutral.iter().zip(self.settings.kind.phases().iter()).map(|(neutral, offset)| {
+            let phase = (self.phase - offset).rem_euclid(1.0);
+            if phase < swing {
+                // Lift the foot and bring it to the front of the stride
+                let progress = phase / swing;
+                let mut foot = Gait::stride_point(neutral, &velocity, stance, progress - 0.5);
+                if moving {
+                    foot.z += self.settings.step_height * (PI * progress).sin();
+                }
+                foot
+            } else {
+                // Push the body with the foot on the ground
+                let progress = (phase - swing) / (1.0 - swing);
+                Gait::stride_point(neutral, &velocity, stance, 0.5 - progress)
+            }
+        }).collect()
+    }
+
+    /**
+     * Move forward in the cycle
+     * @param elapsed       time since the last update
+     */
+    pub fn advance(&mut self, elapsed: Duration) {
+        let cycle = self.settings.cycle_time.as_secs_f32();
+        if cycle > 0.0 {
+            self.phase = (self.phase + elapsed.as_secs_f32() / cycle).rem_euclid(1.0);
+        }
+    }
+
+    /**
+     * Walk for a given time, blocking
+     * @param hexapod           robot to move
+     * @param velocity          wanted move
+     * @param duration          walking time
+     * @param update_period     time between two foot updates
+     * @param clock             clock used to wait
+     * @return if the operation was successful
+     */
+    pub fn walk(&mut self, hexapod: &mut Hexapod, velocity: &Velocity, duration: Duration,
+        update_period: Duration, clock: &dyn Clock) -> Result<()> {
+        let start_phase = self.phase;
+        run_transition(clock, duration, update_period, Easing::Linear, |progress| {
+            self.phase = start_phase;
+            self.advance(duration.mul_f32(progress));
+            hexapod.set_feet(&self.feet(velocity))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn neutral() -> Vec<Point3> {
+        [(60.0f32, 40.0f32, 45.0f32), (0.0, 50.0, 90.0), (-60.0, 40.0, 135.0),
+            (60.0, -40.0, -45.0), (0.0, -50.0, -90.0), (-60.0, -40.0, -135.0)].iter()
+            .map(|(x, y, angle)| {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                Point3::new(x + 120.0 * cos, y + 120.0 * sin, -90.0)
+            }).collect()
+    }
+
+    fn gait_at(kind: GaitKind, phase: f32) -> Gait {
+        let settings = GaitSettings {
+            kind,
+            ..GaitSettings::default()
+        };
+        let mut gait = Gait::new(settings, neutral()).unwrap();
+        gait.phase = phase;
+        gait
+    }
+
+    fn lifted(kind: GaitKind, phase: f32) -> Vec<usize> {
+        let velocity = Velocity { x: 20.0, ..Velocity::default() };
+        gait_at(kind, phase).feet(&velocity).iter().zip(neutral().iter())
+            .enumerate()
+            .filter(|(_, (foot, neutral))| foot.z > neutral.z + 1e-3)
+            .map(|(leg, _)| leg)
+            .collect()
+    }
+
+    #[test]
+    fn stance_pushes_against_velocity() {
+        let forward = Velocity { x: 50.0, ..Velocity::default() };
+        // Leg 0 is on the ground from 0.5 to 1 in a tripod gait
+        let early = gait_at(GaitKind::Tripod, 0.5).feet(&forward)[0];
+        let late = gait_at(GaitKind::Tripod, 0.9).feet(&forward)[0];
+        assert!(early.x > neutral()[0].x && late.x < early.x, "{:?} then {:?}", early, late);
+        assert_eq!(early.z, neutral()[0].z);
+        assert_eq!(late.z, neutral()[0].z);
+
+        let left = Velocity { y: 50.0, ..Velocity::default() };
+        let early = gait_at(GaitKind::Tripod, 0.5).feet(&left)[0];
+        let late = gait_at(GaitKind::Tripod, 0.9).feet(&left)[0];
+        assert!(late.y < early.y);
+
+        // Turning counterclockwise, the feet turn clockwise around the body
+        let turn = Velocity { turn_rate: 20.0, ..Velocity::default() };
+        let early = gait_at(GaitKind::Tripod, 0.5).feet(&turn)[0];
+        let late = gait_at(GaitKind::Tripod, 0.9).feet(&turn)[0];
+        assert!(late.y.atan2(late.x) < early.y.atan2(early.x));
+    }
+
+    #[test]
+    fn swing_reaches_step_height() {
+        let velocity = Velocity { x: 50.0, ..Velocity::default() };
+        let gait = gait_at(GaitKind::Tripod, 0.25);
+        let feet = gait.feet(&velocity);
+        assert!((feet[0].z - (neutral()[0].z + gait.settings.step_height)).abs() < 1e-3);
+        // Mid-swing is over the center of the stride
+        assert!((feet[0].x - neutral()[0].x).abs() < 1e-3);
+        assert_eq!(feet[1].z, neutral()[1].z);
+
+        // Standing still doesn't lift any foot
+        let feet = gait.feet(&Velocity::default());
+        assert_eq!(feet, neutral());
+    }
+
+    #[test]
+    fn legs_are_lifted_in_groups() {
+        assert_eq!(lifted(GaitKind::Tripod, 0.25), vec![0, 2, 4]);
+        assert_eq!(lifted(GaitKind::Tripod, 0.75), vec![1, 3, 5]);
+
+        let wave: Vec<Vec<usize>> = (0..6).map(|step| lifted(GaitKind::Wave, (step as f32 + 0.5) / 6.0)).collect();
+        assert_eq!(wave, vec![vec![5], vec![4], vec![3], vec![2], vec![1], vec![0]]);
+
+        let ripple: Vec<Vec<usize>> = (0..6).map(|step| lifted(GaitKind::Ripple, (step as f32 + 0.5) / 6.0)).collect();
+        for legs in ripple.iter() {
+            assert_eq!(legs.len(), 2);
+            assert!(legs[0] < 3 && legs[1] >= 3, "{:?} not one per side", legs);
+        }
+        let mut lifts: Vec<usize> = ripple.concat();
+        lifts.sort();
+        assert_eq!(lifts, vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5]);
+    }
+
+    #[test]
+    fn walking_moves_the_servos() {
+        let clock = ManualClock::new();
+        let mut hexapod = crate::kinematics::tests::hexapod();
+        let neutral = hexapod.neutral_feet();
+        let mut gait = Gait::new(GaitSettings::default(), neutral.clone()).unwrap();
+        let velocity = Velocity { x: 30.0, ..Velocity::default() };
+        let tick = Duration::from_millis(100);
+        // Feet read back from the servos after each tick of a cycle
+        let mut ticks = Vec::new();
+        for _ in 0..10 {
+            gait.walk(&mut hexapod, &velocity, tick, tick, &clock).unwrap();
+            let feet = hexapod.feet().unwrap();
+            // Servo targets are rounded to 0.25 µs
+            for (foot, wanted) in feet.iter().zip(gait.feet(&velocity)) {
+                assert!(foot.distance(&wanted) < 0.1, "{:?} != {:?}", foot, wanted);
+            }
+            ticks.push(feet);
+        }
+        assert_eq!(clock.now(), Duration::from_secs(1));
+
+        // Tripod: legs 0, 2, 4 swing during the first half of the cycle
+        for (swing, stance, range) in [([0, 2, 4], [1, 3, 5], 0..4), ([1, 3, 5], [0, 2, 4], 5..9)].iter() {
+            for i in range.clone() {
+                for &leg in swing.iter() {
+                    assert!(ticks[i][leg].z > neutral[leg].z + 1.0, "leg {} not lifted at tick {}", leg, i);
+                }
+                for &leg in stance.iter() {
+                    assert!((ticks[i][leg].z - neutral[leg].z).abs() < 0.1, "leg {} lifted at tick {}", leg, i);
+                    if i > range.start {
+                        assert!(ticks[i][leg].x < ticks[i - 1][leg].x, "leg {} pulled forward at tick {}", leg, i);
+                    }
+                }
+            }
+        }
+    }
+}
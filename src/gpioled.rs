@@ -1,41 +1,93 @@
-use sysfs_gpio::{Direction, Pin};
+use crate::error::*;
+pub use crate::hal::State;
+use crate::hal::DigitalOutput;
+use crate::pin::{GpioPin, Pin};
+use crate::sysfs::Sysfs;
 
-use crate::pin::Gpio;
-
-pub enum State {
-    LOW = 0,
-    HIGH = 1
+pub struct GpioLed<O: DigitalOutput = GpioPin> {
+    pub pin: O,
 }
 
-pub struct GpioLed {
-    pub pin: Pin,
-    pub direction: Direction,
+impl GpioLed<GpioPin> {
+    pub fn new(pin: &Pin) -> Result<GpioLed> {
+        GpioLed::new_with_sysfs(pin, Sysfs::default())
+    }
+
+    pub fn new_with_sysfs(pin: &Pin, fs: Sysfs) -> Result<GpioLed> {
+        let gpio = pin.gpio.ok_or_else(|| Error::InvalidGpioPin(pin.name.to_string()))?;
+        Ok(GpioLed::new_from_output(GpioPin::new_with_sysfs(gpio, fs)?))
+    }
 }
 
-impl GpioLed {
-    pub fn new(gpio: Gpio) -> GpioLed {
-        let pin = Pin::new(gpio as u64);
-        if !pin.is_exported() {
-            match pin.export() {
-                Ok(()) => info!("Gpio {} exported!", pin.get_pin()),
-                Err(err) => error!("Gpio {} could not be exported: {}", pin.get_pin(), err),
-            }
+impl<O: DigitalOutput> GpioLed<O> {
+    pub fn new_from_output(pin: O) -> GpioLed<O> {
+        GpioLed {
+            pin
         }
-        match pin.get_direction() {
-            Ok(direction) => {
-                if direction != Direction::Out {
-                    pin.set_direction(Direction::Out).ok().expect("Cannot set direction for Gpio");
-                }
-            },
-            Err(err) => error!("Gpio {} cannot get direction: {}", pin.get_pin(), err),
+    }
+
+    pub fn set_state(&mut self, state: State) -> Result<()> {
+        self.pin.set_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pin::Gpio;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// Fake tree with gpio60 (P9_12), exported with a direction if given
+    fn fake_tree(name: &str, direction: Option<&str>) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cucaracha-gpio-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sys/class/gpio")).unwrap();
+        if let Some(direction) = direction {
+            fs::create_dir_all(root.join("sys/class/gpio/gpio60")).unwrap();
+            fs::write(root.join("sys/class/gpio/gpio60/direction"), direction).unwrap();
+            fs::write(root.join("sys/class/gpio/gpio60/value"), "0\n").unwrap();
         }
-        GpioLed {
-            pin,
-            direction: Direction::Out
+        root
+    }
+
+    fn read(root: &Path, path: &str) -> String {
+        fs::read_to_string(root.join(path)).unwrap()
+    }
+
+    #[test]
+    fn unexported_gpio_is_exported() {
+        let root = fake_tree("export", None);
+        let led = GpioLed::new_with_sysfs(Gpio::P9_12.pin(), Sysfs::new(&root));
+        assert_eq!(read(&root, "sys/class/gpio/export"), "60");
+        // The fake kernel doesn't create gpio60, so its direction can't be read
+        match led {
+            Err(Error::Sysfs { path, .. }) => assert!(path.ends_with("sys/class/gpio/gpio60/direction"), "{}", path),
+            _ => panic!("gpio60 should be missing"),
         }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn exported_gpio_is_set_as_output() {
+        let root = fake_tree("input", Some("in\n"));
+        let mut led = GpioLed::new_with_sysfs(Gpio::P9_12.pin(), Sysfs::new(&root)).unwrap();
+        assert!(!root.join("sys/class/gpio/export").exists());
+        assert_eq!(read(&root, "sys/class/gpio/gpio60/direction"), "out");
+        led.set_state(State::HIGH).unwrap();
+        assert_eq!(read(&root, "sys/class/gpio/gpio60/value"), "1");
+        led.set_state(State::LOW).unwrap();
+        assert_eq!(read(&root, "sys/class/gpio/gpio60/value"), "0");
+        fs::remove_dir_all(&root).unwrap();
     }
 
-    pub fn set_state(&self, state: State) -> bool {
-        self.pin.set_value(state as u8).is_ok()
+    #[test]
+    fn output_is_not_configured_again() {
+        let root = fake_tree("output", Some("out\n"));
+        GpioLed::new_with_sysfs(Gpio::P9_12.pin(), Sysfs::new(&root)).unwrap();
+        assert_eq!(read(&root, "sys/class/gpio/gpio60/direction"), "out\n");
+        assert!(matches!(GpioLed::new_with_sysfs(Pin::from_name("P9_1").unwrap(), Sysfs::new(&root)),
+            Err(Error::InvalidGpioPin(_))));
+        fs::remove_dir_all(&root).unwrap();
     }
-}
\ No newline at end of file
+}
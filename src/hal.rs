@@ -0,0 +1,93 @@
+use crate::error::*;
+
+/**
+ * Level of a digital output
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum State {
+    LOW = 0,
+    HIGH = 1
+}
+
+/**
+ * A PWM output, e.g. a BeagleBone pwmchip channel or a PCA9685 output
+ */
+pub trait PwmChannel {
+    /**
+     * @return the current period in ns
+     */
+    fn get_period_ns(&self) -> Result<u32>;
+
+    /**
+     * @return the current duty cycle in ns
+     */
+    fn get_duty_ns(&self) -> Result<u32>;
+
+    /**
+     * Change the period, keeping the same duty ratio
+     * @param period_ns     wanted period
+     * @return if the operation was successful
+     */
+    fn set_period_ns(&mut self, period_ns: u32) -> Result<()>;
+
+    /**
+     * Change the duty cycle
+     * @param duty_ns       wanted duty cycle
+     * @return if the operation was successful
+     */
+    fn set_duty_ns(&mut self, duty_ns: u32) -> Result<()>;
+
+    /**
+     * Configure and enable the output
+     * @param duty_ns       initial duty cycle
+     * @param period_ns     initial period
+     * @return if the operation was successful
+     */
+    fn start_pwm(&mut self, duty_ns: u32, period_ns: u32) -> Result<()>;
+
+    /**
+     * @return the period imposed by another output sharing the same clock, if any
+     */
+    fn shared_period_ns(&self) -> Option<u32> {
+        None
+    }
+}
+
+/**
+ * A digital output, e.g. a BeagleBone GPIO
+ */
+pub trait DigitalOutput {
+    /**
+     * Change the level of the output
+     * @param state         wanted level
+     * @return if the operation was successful
+     */
+    fn set_state(&mut self, state: State) -> Result<()>;
+}
+
+/**
+ * Something able to move a servo, e.g. a PWM output or a Maestro channel
+ */
+pub trait ServoDriver {
+    /**
+     * @return the range of the servo in degrees
+     */
+    fn range(&self) -> f32;
+
+    /**
+     * Change the servo position
+     * @param position      wanted position between 0 and range()
+     * @return if the operation was successful
+     */
+    fn set_position(&mut self, position: f32) -> Result<()>;
+
+    /**
+     * @return the current position in degrees, between 0 and range()
+     */
+    fn position(&mut self) -> Result<f32>;
+
+    /**
+     * @return the current pulse width sent to the servo in µs
+     */
+    fn pulse_width_us(&mut self) -> Result<f32>;
+}
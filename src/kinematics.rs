@@ -0,0 +1,458 @@
+use crate::error::*;
+use crate::servo::ServoGroup;
+
+/**
+ * A point in millimeters. In body coordinates, x points forward, y to the
+ * left and z up, from the center of the body.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Point3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Point3 {
+    /**
+     * Get a new point
+     * @return the new point
+     */
+    pub fn new(x: f32, y: f32, z: f32) -> Point3 {
+        Point3 {
+            x,
+            y,
+            z
+        }
+    }
+
+    /**
+     * @return the distance to another point
+     */
+    pub fn distance(&self, other: &Point3) -> f32 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2) + (self.z - other.z).powi(2)).sqrt()
+    }
+}
+
+/**
+ * Angles of the joints of a leg in degrees
+ * coxa: horizontal rotation, 0 when the leg points to its mounting angle,
+ * positive counterclockwise seen from above
+ * femur: elevation of the femur, 0 when horizontal, positive up
+ * tibia: 0 when the tibia is perpendicular to the femur, positive when it
+ * opens outward
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct JointAngles {
+    pub coxa: f32,
+    pub femur: f32,
+    pub tibia: f32,
+}
+
+/**
+ * Dimensions and position of a leg, lengths in millimeters
+ * mount: position of the coxa joint in body coordinates
+ * mount_angle: direction of the leg at coxa = 0, in degrees from the x
+ * axis, counterclockwise seen from above
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LegGeometry {
+    pub coxa_length: f32,
+    pub femur_length: f32,
+    pub tibia_length: f32,
+    pub mount: Point3,
+    pub mount_angle: f32,
+}
+
+/**
+ * Link between a joint and the servo moving it
+ * servo: index of the servo in the servos of the robot
+ * center: servo position for a joint angle of 0
+ * inverted: if the servo position decreases when the joint angle increases
+ * min_angle/max_angle: mechanical limits of the joint in degrees
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JointMapping {
+    pub servo: usize,
+    pub center: f32,
+    pub inverted: bool,
+    pub min_angle: f32,
+    pub max_angle: f32,
+}
+
+impl JointMapping {
+    /**
+     * Get a new mapping centered on 90 degrees, +/- 90 degrees
+     * @param servo     index of the servo
+     * @return the new mapping
+     */
+    pub fn new(servo: usize) -> JointMapping {
+        JointMapping {
+            servo,
+            center: 90.0,
+            inverted: false,
+            min_angle: -90.0,
+            max_angle: 90.0,
+        }
+    }
+
+    /**
+     * @param angle     joint angle in degrees
+     * @return the servo position for this angle
+     */
+    pub fn position(&self, angle: f32) -> f32 {
+        match self.inverted {
+            true => self.center - angle,
+            false => self.center + angle,
+        }
+    }
+
+    /**
+     * @param position  servo position
+     * @return the joint angle for this position
+     */
+    pub fn angle(&self, position: f32) -> f32 {
+        match self.inverted {
+            true => self.center - position,
+            false => position - self.center,
+        }
+    }
+}
+
+/**
+ * A leg made of a coxa, a femur and a tibia, with the servos of its joints
+ * in the order coxa, femur, tibia
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leg {
+    pub name: String,
+    pub geometry: LegGeometry,
+    pub joints: [JointMapping; 3],
+}
+
+impl Leg {
+    /**
+     * Create a new leg
+     * @param name      name used in errors, e.g. "left front"
+     * @param geometry  dimensions of the leg
+     * @param joints    servos of the coxa, femur and tibia
+     * @return the new leg
+     */
+    pub fn new(name: &str, geometry: LegGeometry, joints: [JointMapping; 3]) -> Leg {
+        Leg {
+            name: String::from(name),
+            geometry,
+            joints
+        }
+    }
+
+    fn unreachable(&self, foot: &Point3, reason: &str) -> Error {
+        Error::Unreachable(format!("{} leg can't reach ({:.1}, {:.1}, {:.1}): {}",
+            self.name, foot.x, foot.y, foot.z, reason))
+    }
+
+    /**
+     * Inverse kinematics, with the knee above the foot
+     * @param foot      wanted foot position in body coordinates
+     * @return the joint angles, Error::Unreachable if the foot is out of
+     * reach or a joint would pass its limits
+     */
+    pub fn inverse(&self, foot: &Point3) -> Result<JointAngles> {
+        let geometry = &self.geometry;
+        // Move to the frame of the leg, x pointing along the leg
+        let (sin, cos) = geometry.mount_angle.to_radians().sin_cos();
+        let (dx, dy) = (foot.x - geometry.mount.x, foot.y - geometry.mount.y);
+        let x = cos * dx + sin * dy;
+        let y = -sin * dx + cos * dy;
+        let z = foot.z - geometry.mount.z;
+
+        let horizontal = x.hypot(y);
+        if horizontal < f32::EPSILON {
+            return Err(self.unreachable(foot, "foot under the coxa joint"));
+        }
+        let coxa = y.atan2(x);
+        // Femur and tibia work in the vertical plane of the leg
+        let r = horizontal - geometry.coxa_length;
+        let distance = r.hypot(z);
+        let (femur_length, tibia_length) = (geometry.femur_length, geometry.tibia_length);
+        if distance > femur_length + tibia_length {
+            return Err(self.unreachable(foot, "too far"));
+        }
+        if distance < (femur_length - tibia_length).abs() || distance < f32::EPSILON {
+            return Err(self.unreachable(foot, "too close"));
+        }
+        let femur_cos = (femur_length.powi(2) + distance.powi(2) - tibia_length.powi(2))
+            / (2.0 * femur_length * distance);
+        let femur = z.atan2(r) + femur_cos.clamp(-1.0, 1.0).acos();
+        let knee_cos = (femur_length.powi(2) + tibia_length.powi(2) - distance.powi(2))
+            / (2.0 * femur_length * tibia_length);
+        let knee = knee_cos.clamp(-1.0, 1.0).acos();
+
+        let angles = JointAngles {
+            coxa: coxa.to_degrees(),
+            femur: femur.to_degrees(),
+            tibia: knee.to_degrees() - 90.0,
+        };
+        let limits = [("coxa", angles.coxa), ("femur", angles.femur), ("tibia", angles.tibia)];
+        for (joint, (name, angle)) in self.joints.iter().zip(limits.iter()) {
+            if *angle < joint.min_angle || *angle > joint.max_angle {
+                return Err(self.unreachable(foot, &format!("{} angle {:.1} out of [{}, {}]",
+                    name, angle, joint.min_angle, joint.max_angle)));
+            }
+        }
+        Ok(angles)
+    }
+
+    /**
+     * Forward kinematics
+     * @param angles    joint angles
+     * @return the foot position in body coordinates
+     */
+    pub fn forward(&self, angles: &JointAngles) -> Point3 {
+        let geometry = &self.geometry;
+        let femur = angles.femur.to_radians();
+        // Absolute elevation of the tibia
+        let tibia = femur + (angles.tibia + 90.0).to_radians() - std::f32::consts::PI;
+        let r = geometry.coxa_length + geometry.femur_length * femur.cos() + geometry.tibia_length * tibia.cos();
+        let z = geometry.femur_length * femur.sin() + geometry.tibia_length * tibia.sin();
+
+        let (sin, cos) = (geometry.mount_angle + angles.coxa).to_radians().sin_cos();
+        Point3 {
+            x: geometry.mount.x + r * cos,
+            y: geometry.mount.y + r * sin,
+            z: geometry.mount.z + z,
+        }
+    }
+
+    /**
+     * @param angles    joint angles
+     * @return the index and position of the coxa, femur and tibia servos
+     */
+    pub fn servo_positions(&self, angles: &JointAngles) -> [(usize, f32); 3] {
+        let [coxa, femur, tibia] = &self.joints;
+        [
+            (coxa.servo, coxa.position(angles.coxa)),
+            (femur.servo, femur.position(angles.femur)),
+            (tibia.servo, tibia.position(angles.tibia)),
+        ]
+    }
+
+    /**
+     * @param positions positions of the coxa, femur and tibia servos
+     * @return the joint angles
+     */
+    pub fn joint_angles(&self, positions: [f32; 3]) -> JointAngles {
+        let [coxa, femur, tibia] = &self.joints;
+        JointAngles {
+            coxa: coxa.angle(positions[0]),
+            femur: femur.angle(positions[1]),
+            tibia: tibia.angle(positions[2]),
+        }
+    }
+}
+
+/**
+ * The legs of a robot with the servos moving them. JointMapping::servo
+ * indexes the servos of all the groups, in the order of the groups.
+ */
+pub struct Hexapod {
+    pub legs: Vec<Leg>,
+    groups: Vec<ServoGroup>,
+}
+
+impl Hexapod {
+    /**
+     * Create a new robot
+     * @param legs      legs of the robot
+     * @param groups    servos of the legs, one group per Maestro
+     * @return the new robot, Error::InvalidServoGroup if a servo is not
+     * moved by exactly one joint
+     */
+    pub fn new(legs: Vec<Leg>, mut groups: Vec<ServoGroup>) -> Result<Hexapod> {
+        let count: usize = groups.iter_mut().map(|group| group.servos().len()).sum();
+        let mut used = vec![false; count];
+        for joint in legs.iter().flat_map(|leg| leg.joints.iter()) {
+            match used.get_mut(joint.servo) {
+                Some(used) if !*used => *used = true,
+                Some(_) => return Err(Error::InvalidServoGroup(format!("servo {} moved by several joints", joint.servo))),
+                None => return Err(Error::InvalidServoGroup(format!("no servo {}, only {} servos", joint.servo, count))),
+            }
+        }
+        if let Some(servo) = used.iter().position(|used| !used) {
+            return Err(Error::InvalidServoGroup(format!("servo {} is not moved by any joint", servo)));
+        }
+        Ok(Hexapod {
+            legs,
+            groups
+        })
+    }
+
+    /**
+     * @return the servo groups of the robot
+     */
+    pub fn groups(&mut self) -> &mut [ServoGroup] {
+        &mut self.groups
+    }
+
+    /**
+     * @return the foot positions with all joints at 0, in the order of the legs
+     */
+    pub fn neutral_feet(&self) -> Vec<Point3> {
+        self.legs.iter().map(|leg| leg.forward(&JointAngles::default())).collect()
+    }
+
+    /**
+     * Move every foot at once. Nothing moves if a foot is out of reach.
+     * @param feet      wanted foot positions in body coordinates, in the order of the legs
+     * @return if the operation was successful
+     */
+    pub fn set_feet(&mut self, feet: &[Point3]) -> Result<()> {
+        if feet.len() != self.legs.len() {
+            return Err(Error::InvalidServoGroup(format!("{} feet for {} legs", feet.len(), self.legs.len())));
+        }
+        let mut positions = Vec::new();
+        for (leg, foot) in self.legs.iter().zip(feet) {
+            positions.extend_from_slice(&leg.servo_positions(&leg.inverse(foot)?));
+        }
+        positions.sort_by_key(|(servo, _)| *servo);
+
+        let mut positions = positions.into_iter().map(|(_, position)| position);
+        for group in self.groups.iter_mut() {
+            let group_positions: Vec<f32> = positions.by_ref().take(group.servos().len()).collect();
+            group.set_positions(&group_positions)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * @return the foot positions computed from the servo positions, in the order of the legs
+     */
+    pub fn feet(&mut self) -> Result<Vec<Point3>> {
+        let mut positions = Vec::new();
+        for group in self.groups.iter_mut() {
+            positions.extend(group.positions()?);
+        }
+        Ok(self.legs.iter().map(|leg| {
+            let [coxa, femur, tibia] = &leg.joints;
+            leg.forward(&leg.joint_angles([positions[coxa.servo], positions[femur.servo], positions[tibia.servo]]))
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::emulator::MaestroEmulator;
+    use crate::maestro::*;
+    use crate::servo::Servo;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const MOUNTS: [(f32, f32, f32); 6] = [(60.0, 40.0, 45.0), (0.0, 50.0, 90.0), (-60.0, 40.0, 135.0),
+        (60.0, -40.0, -45.0), (0.0, -50.0, -90.0), (-60.0, -40.0, -135.0)];
+
+    fn leg(index: usize) -> Leg {
+        let (x, y, mount_angle) = MOUNTS[index];
+        let geometry = LegGeometry {
+            coxa_length: 30.0,
+            femur_length: 60.0,
+            tibia_length: 90.0,
+            mount: Point3::new(x, y, 0.0),
+            mount_angle,
+        };
+        let servo = 3 * index;
+        Leg::new("test", geometry, [JointMapping::new(servo), JointMapping::new(servo + 1), JointMapping::new(servo + 2)])
+    }
+
+    fn servo_group(channels: usize) -> ServoGroup {
+        let emulator = MaestroEmulator::new(0x0c, channels);
+        // Servos without speed limit reach their target in one frame
+        emulator.set_auto_advance(Duration::from_millis(10));
+        let maestro = Maestro::new_with_port(Box::new(emulator), MaestroSettings::default()).unwrap();
+        let maestro = Arc::new(Mutex::new(maestro));
+        ServoGroup::new((0..channels as u8).map(|channel| Servo::new_from_maestro(180.0, channel, maestro.clone()))
+            .collect()).unwrap()
+    }
+
+    /**
+     * Robot with 6 legs on one emulated 18 channel Maestro, servo 3 * leg + joint
+     */
+    pub(crate) fn hexapod() -> Hexapod {
+        Hexapod::new((0..6).map(leg).collect(), vec![servo_group(18)]).unwrap()
+    }
+
+    fn is_unreachable(res: Result<JointAngles>, reason: &str) -> bool {
+        match res {
+            Err(Error::Unreachable(message)) => message.contains(reason),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_forward() {
+        let leg = leg(0);
+        // Feet are around 150 mm away, about 1e-5 mm apart in f32
+        for angles in [(0.0, 0.0, 0.0), (20.0, 10.0, -30.0), (-45.0, -20.0, 15.0), (60.0, 40.0, 20.0)].iter() {
+            let angles = JointAngles { coxa: angles.0, femur: angles.1, tibia: angles.2 };
+            let foot = leg.forward(&angles);
+            let back = leg.inverse(&foot).unwrap();
+            for (a, b) in [(back.coxa, angles.coxa), (back.femur, angles.femur), (back.tibia, angles.tibia)].iter() {
+                assert!((a - b).abs() < 1e-4, "{:?} gave {:?}", angles, back);
+            }
+            assert!(leg.forward(&back).distance(&foot) < 1e-4);
+        }
+        // Neutral foot, 45° from the mount: coxa + femur straight out, tibia straight down
+        let neutral = leg.forward(&JointAngles::default());
+        let reach = 90.0 / 2f32.sqrt();
+        assert!(neutral.distance(&Point3::new(60.0 + reach, 40.0 + reach, -90.0)) < 1e-4);
+    }
+
+    #[test]
+    fn unreachable_feet_are_rejected() {
+        let leg = leg(0);
+        assert!(is_unreachable(leg.inverse(&Point3::new(400.0, 400.0, -90.0)), "too far"));
+        // Closer than femur - tibia from the femur joint
+        assert!(is_unreachable(leg.inverse(&Point3::new(60.0 + 30.0 / 2f32.sqrt(), 40.0 + 30.0 / 2f32.sqrt(), -10.0)),
+            "too close"));
+        assert!(is_unreachable(leg.inverse(&Point3::new(60.0, 40.0, -90.0)), "under the coxa"));
+        // Behind the mount, the coxa would turn by 180°
+        assert!(is_unreachable(leg.inverse(&Point3::new(0.0, -20.0, -90.0)), "coxa angle"));
+
+        let mut limited = leg.clone();
+        limited.joints[2].max_angle = 10.0;
+        assert!(is_unreachable(limited.inverse(&limited.forward(&JointAngles { tibia: 20.0, ..JointAngles::default() })),
+            "tibia angle"));
+    }
+
+    #[test]
+    fn joint_mapping_converts_both_ways() {
+        let mapping = JointMapping::new(4);
+        assert_eq!((mapping.position(30.0), mapping.angle(120.0)), (120.0, 30.0));
+        let inverted = JointMapping { inverted: true, center: 100.0, ..mapping };
+        assert_eq!((inverted.position(30.0), inverted.angle(70.0)), (70.0, 30.0));
+        for angle in [-90.0, -12.5, 0.0, 45.0, 90.0].iter() {
+            assert_eq!(inverted.angle(inverted.position(*angle)), *angle);
+            assert_eq!(mapping.angle(mapping.position(*angle)), *angle);
+        }
+
+        let leg = leg(1);
+        let angles = JointAngles { coxa: 10.0, femur: -20.0, tibia: 30.0 };
+        let positions = leg.servo_positions(&angles);
+        assert_eq!(positions, [(3, 100.0), (4, 70.0), (5, 120.0)]);
+        assert_eq!(leg.joint_angles([positions[0].1, positions[1].1, positions[2].1]), angles);
+    }
+
+    #[test]
+    fn every_servo_needs_exactly_one_joint() {
+        let legs: Vec<Leg> = (0..6).map(leg).collect();
+        let hexapod = Hexapod::new(legs.clone(), vec![servo_group(12), servo_group(6)]).unwrap();
+        assert_eq!(hexapod.neutral_feet()[0], legs[0].forward(&JointAngles::default()));
+
+        let mut shared = legs.clone();
+        shared[5].joints[2].servo = 0;
+        assert!(matches!(Hexapod::new(shared, vec![servo_group(18)]), Err(Error::InvalidServoGroup(_))));
+        assert!(matches!(Hexapod::new(legs[..5].to_vec(), vec![servo_group(18)]), Err(Error::InvalidServoGroup(_))));
+        let mut missing = legs.clone();
+        missing[5].joints[2].servo = 18;
+        assert!(matches!(Hexapod::new(missing, vec![servo_group(18)]), Err(Error::InvalidServoGroup(_))));
+    }
+}
@@ -0,0 +1,21 @@
+#[macro_use]
+extern crate log;
+extern crate serial;
+
+pub mod beaglebone;
+pub mod clock;
+pub mod emulator;
+pub mod error;
+pub mod gait;
+pub mod gpioled;
+pub mod hal;
+pub mod kinematics;
+pub mod maestro;
+pub mod motion;
+pub mod pin;
+pub mod pose;
+pub mod pwmchip;
+pub mod pwmled;
+pub mod rgbled;
+pub mod servo;
+pub mod sysfs;
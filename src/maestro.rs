@@ -1,45 +1,545 @@
 
+use crate::clock::*;
+use crate::error::*;
+use crate::sysfs::Sysfs;
+use std::io;
 use std::io::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 use serial::prelude::*;
 
+/**
+ * Errors reported by the Maestro (Get Errors command)
+ */
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct MaestroErrors(pub u16);
+
+impl MaestroErrors {
+    pub const SERIAL_SIGNAL: MaestroErrors = MaestroErrors(1 << 0);
+    pub const SERIAL_OVERRUN: MaestroErrors = MaestroErrors(1 << 1);
+    pub const SERIAL_BUFFER_FULL: MaestroErrors = MaestroErrors(1 << 2);
+    pub const SERIAL_CRC: MaestroErrors = MaestroErrors(1 << 3);
+    pub const SERIAL_PROTOCOL: MaestroErrors = MaestroErrors(1 << 4);
+    pub const SERIAL_TIMEOUT: MaestroErrors = MaestroErrors(1 << 5);
+    pub const SCRIPT_STACK: MaestroErrors = MaestroErrors(1 << 6);
+    pub const SCRIPT_CALL_STACK: MaestroErrors = MaestroErrors(1 << 7);
+    pub const SCRIPT_PROGRAM_COUNTER: MaestroErrors = MaestroErrors(1 << 8);
+
+    const NAMES: [(MaestroErrors, &'static str); 9] = [
+        (MaestroErrors::SERIAL_SIGNAL, "serial signal"),
+        (MaestroErrors::SERIAL_OVERRUN, "serial overrun"),
+        (MaestroErrors::SERIAL_BUFFER_FULL, "serial buffer full"),
+        (MaestroErrors::SERIAL_CRC, "serial CRC"),
+        (MaestroErrors::SERIAL_PROTOCOL, "serial protocol"),
+        (MaestroErrors::SERIAL_TIMEOUT, "serial timeout"),
+        (MaestroErrors::SCRIPT_STACK, "script stack"),
+        (MaestroErrors::SCRIPT_CALL_STACK, "script call stack"),
+        (MaestroErrors::SCRIPT_PROGRAM_COUNTER, "script program counter"),
+    ];
+
+    /**
+     * @return if no error is set
+     */
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /**
+     * @param other     errors to check
+     * @return if all errors in other are set
+     */
+    pub fn contains(&self, other: MaestroErrors) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /**
+     * @return a readable name for each error set
+     */
+    pub fn names(&self) -> Vec<&'static str> {
+        MaestroErrors::NAMES.iter()
+            .filter(|(error, _)| self.contains(*error))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/**
+ * State of the script running on the Maestro
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ScriptStatus {
+    Running,
+    Stopped,
+}
+
+/**
+ * Serial link used to talk to a Maestro: a real serial port or an emulator
+ */
+pub trait MaestroPort: Read + Write + Send {
+    /**
+     * @return how long a read waits for data
+     */
+    fn timeout(&self) -> Duration;
+
+    /**
+     * Change how long a read waits for data
+     * @param timeout       new timeout
+     * @return if the operation was successful
+     */
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()>;
+
+    /**
+     * Drop every byte received but not read yet
+     * @return if the operation was successful
+     */
+    fn clear_input(&mut self) -> Result<()> {
+        let timeout = self.timeout();
+        self.set_timeout(Duration::from_millis(0))?;
+        let mut buf = [0; 64];
+        let mut dropped = 0;
+        while let Ok(len) = self.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+            dropped += len;
+        }
+        if dropped > 0 {
+            warn!("Dropped {} unexpected bytes from the Maestro", dropped);
+        }
+        self.set_timeout(timeout)
+    }
+}
+
+impl MaestroPort for serial::SystemPort {
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        SerialPort::set_timeout(self, timeout)?;
+        Ok(())
+    }
+}
+
+/**
+ * Compute the CRC-7 appended to commands when the Maestro is configured for
+ * it (polynomial 0x91, LSB first)
+ * @param message       packet to protect
+ * @return the CRC byte
+ */
+pub fn crc7(message: &[u8]) -> u8 {
+    let mut crc = 0;
+    for byte in message {
+        crc ^= byte;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc ^= 0x91;
+            }
+            crc >>= 1;
+        }
+    }
+    crc
+}
+
+/**
+ * Serial protocol the Maestro is configured for
+ * Pololu: [0xaa, device, command, data...], works with daisy-chained boards
+ * Compact: [command | 0x80, data...], 2 bytes shorter per command
+ * MiniSsc: [0xff, channel + offset, 0-254], only supports set_target. The
+ * value is scaled between the Maestro min_target and max_target (not the
+ * channel ranges), the real pulse width depends on the range configured
+ * on the board. Mini SSC packets never carry a CRC.
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MaestroProtocol {
+    Pololu,
+    Compact,
+    MiniSsc { offset: u8 },
+}
+
+/**
+ * Unit of a channel target
+ * QuarterMicros: raw Maestro value
+ * Micros: pulse width in µs
+ * Degrees: position between 0 and ChannelRange::degrees
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TargetUnit {
+    QuarterMicros,
+    Micros,
+    Degrees,
+}
+
+/**
+ * Software limits of a channel, in quarter-µs
+ * min_target/max_target: targets are clamped in this range, they are also
+ * the pulse widths of 0 and degrees
+ * home: target used by return_home
+ * degrees: angular range of the servo between min_target and max_target
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelRange {
+    pub min_target: u16,
+    pub max_target: u16,
+    pub home: u16,
+    pub degrees: f32,
+}
+
+impl ChannelRange {
+    /**
+     * Get a new range, with a centered home and 180 degrees
+     * @param min_target    lowest target
+     * @param max_target    highest target
+     * @return the new range
+     */
+    pub fn new(min_target: u16, max_target: u16) -> ChannelRange {
+        ChannelRange {
+            min_target,
+            max_target,
+            home: min_target + (max_target.max(min_target) - min_target) / 2,
+            degrees: 180.0,
+        }
+    }
+
+    /**
+     * Convert a value to a target
+     * @param value         value to convert
+     * @param unit          unit of value
+     * @return the target in quarter-µs, clamped in the range
+     */
+    pub fn to_target(&self, value: f32, unit: TargetUnit) -> u16 {
+        let target = match unit {
+            TargetUnit::QuarterMicros => value,
+            TargetUnit::Micros => value * 4.0,
+            TargetUnit::Degrees => {
+                let ratio = value.clamp(0.0, self.degrees) / self.degrees;
+                self.min_target as f32 + (self.max_target as f32 - self.min_target as f32) * ratio
+            },
+        };
+        self.clamp(target.round().clamp(0.0, u16::MAX as f32) as u16)
+    }
+
+    /**
+     * Convert a target to a value
+     * @param target        target in quarter-µs
+     * @param unit          wanted unit
+     * @return the value in unit
+     */
+    pub fn from_target(&self, target: u16, unit: TargetUnit) -> f32 {
+        match unit {
+            TargetUnit::QuarterMicros => target as f32,
+            TargetUnit::Micros => target as f32 / 4.0,
+            TargetUnit::Degrees => {
+                let range = (self.max_target as f32 - self.min_target as f32).max(1.0);
+                let ratio = (target as f32 - self.min_target as f32) / range;
+                ratio.clamp(0.0, 1.0) * self.degrees
+            },
+        }
+    }
+
+    /**
+     * @param target        target in quarter-µs
+     * @return the target clamped in the range
+     */
+    pub fn clamp(&self, target: u16) -> u16 {
+        target.clamp(self.min_target, self.max_target.max(self.min_target))
+    }
+}
+
 /**
  * Represent what the end user see. servo_range should be the most interesting
  * setting to change.
+ * port_settings: baud rate and framing of the serial port, the USB Command
+ * port ignores them
+ * detect_baud: send the 0xAA baud rate detection byte when the port is
+ * opened, for boards in "UART, detect baud rate" mode
  */
 pub struct MaestroSettings {
     pub port: String,
     pub device: u8,
     pub min_target: u16,
     pub max_target: u16,
+    pub timeout: Duration,
+    pub protocol: MaestroProtocol,
+    pub crc: bool,
+    pub port_settings: serial::PortSettings,
+    pub detect_baud: bool,
+}
+
+impl Default for MaestroSettings {
+    fn default() -> MaestroSettings {
+        MaestroSettings {
+            port: String::from("/dev/ttyACM0"),
+            device: 0x0c,
+            min_target: 2000,
+            max_target: 10000,
+            timeout: Duration::from_millis(100),
+            protocol: MaestroProtocol::Pololu,
+            crc: false,
+            port_settings: serial::PortSettings {
+                baud_rate:     serial::Baud9600,
+                char_size:     serial::Bits8,
+                parity:        serial::ParityNone,
+                stop_bits:     serial::Stop1,
+                flow_control:  serial::FlowNone,
+            },
+            detect_baud: false,
+        }
+    }
+}
+
+/// USB vendor ID of Pololu
+const POLOLU_VENDOR_ID: &str = "1ffb";
+
+/**
+ * Serial ports created by a Maestro over USB
+ * Command: receives the commands of this crate (interface 0)
+ * Ttl: bridged to the TX/RX pins of the board (interface 2)
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MaestroPortKind {
+    Command,
+    Ttl,
+}
+
+/**
+ * A serial port of a Maestro plugged over USB
+ * path: device to open (e.g. /dev/ttyACM0)
+ * kind: role of the port
+ * product: USB product ID, 0x89 for the Micro Maestro 6, 0x8a-0x8c for
+ * the Mini Maestro 12, 18 and 24
+ * serial: serial number of the board, to tell several boards apart
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MaestroPortInfo {
+    pub path: String,
+    pub kind: MaestroPortKind,
+    pub product: u16,
+    pub serial: Option<String>,
+}
+
+/**
+ * List the Maestro serial ports by walking /sys/class/tty
+ * @param fs        sysfs tree to walk
+ * @return the ports found, sorted by device name
+ */
+pub fn find_ports(fs: &Sysfs) -> Result<Vec<MaestroPortInfo>> {
+    let mut ports = Vec::new();
+    for name in fs.list("/sys/class/tty")? {
+        // device links to the USB interface, its parent is the USB device
+        let interface = format!("/sys/class/tty/{}/device", name);
+        let usb_device = format!("{}/..", interface);
+        match fs.read(&format!("{}/idVendor", usb_device)) {
+            Ok(ref vendor) if vendor == POLOLU_VENDOR_ID => {},
+            _ => continue,
+        }
+        let kind = match fs.read(&format!("{}/bInterfaceNumber", interface))?.as_str() {
+            "00" => MaestroPortKind::Command,
+            "02" => MaestroPortKind::Ttl,
+            _ => continue,
+        };
+        let product = fs.read(&format!("{}/idProduct", usb_device))?;
+        ports.push(MaestroPortInfo {
+            path: format!("/dev/{}", name),
+            kind,
+            product: u16::from_str_radix(&product, 16).unwrap_or(0),
+            serial: fs.read(&format!("{}/serial", usb_device)).ok(),
+        });
+    }
+    Ok(ports)
+}
+
+/**
+ * Find the first Maestro serial port of a given kind
+ * @param fs        sysfs tree to walk
+ * @param kind      wanted port
+ * @return the path of the device
+ */
+pub fn find_port(fs: &Sysfs, kind: MaestroPortKind) -> Result<String> {
+    find_ports(fs)?.into_iter()
+        .find(|port| port.kind == kind)
+        .map(|port| port.path)
+        .ok_or_else(|| Error::NotFound(format!("no Maestro {:?} port in /sys/class/tty", kind)))
+}
+
+/**
+ * Read a complete answer from a Maestro
+ * @param port      port to read
+ * @param buf       buffer to fill
+ * @return if the operation was successful, Error::Timeout if the
+ * Maestro didn't answer in time
+ */
+fn receive(port: &mut dyn MaestroPort, buf: &mut [u8]) -> Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        match port.read(&mut buf[read..]) {
+            Ok(0) => return Err(Error::Timeout),
+            Ok(len) => read += len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref err) if err.kind() == io::ErrorKind::TimedOut
+                || err.kind() == io::ErrorKind::WouldBlock => {
+                warn!("Maestro answered {} bytes out of {}", read, buf.len());
+                return Err(Error::Timeout);
+            },
+            Err(err) => return Err(serial::Error::from(err).into()),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * A serial line, shared by all the Maestros daisy-chained on it. Each
+ * Maestro is addressed by its device number with the Pololu protocol.
+ */
+#[derive(Clone)]
+pub struct MaestroBus {
+    port: Arc<Mutex<Box<dyn MaestroPort>>>,
+}
+
+impl MaestroBus {
+    /**
+     * Open and configure a serial port
+     * @param settings      settings.port is the device to open, with
+     * settings.port_settings. The baud rate is detected if settings.detect_baud.
+     * @return the new bus
+     */
+    pub fn open(settings: &MaestroSettings) -> Result<MaestroBus> {
+        // Configure the serial port
+        let mut port = serial::open(&settings.port)?;
+        port.configure(&settings.port_settings)?;
+        let bus = MaestroBus::new(Box::new(port));
+        if settings.detect_baud {
+            bus.detect_baud(settings)?;
+        }
+        Ok(bus)
+    }
+
+    /**
+     * Send the baud rate detection byte, then check a Maestro answers
+     * Get Errors at this rate
+     * @param settings      settings.device is the board to check
+     * @return if the operation was successful, Error::Protocol if the
+     * board didn't answer
+     * @note this clears the errors of the board
+     */
+    pub fn detect_baud(&self, settings: &MaestroSettings) -> Result<()> {
+        self.lock().write_all(&[0xaa]).map_err(serial::Error::from)?;
+        let mut maestro = self.maestro(MaestroSettings {
+            port: String::new(),
+            protocol: MaestroProtocol::Pololu,
+            ..*settings
+        })?;
+        match maestro.get_errors() {
+            Ok(_) => Ok(()),
+            Err(Error::Timeout) => Err(Error::Protocol(format!(
+                "Maestro {} didn't answer after the baud rate detection", settings.device))),
+            Err(err) => Err(err),
+        }
+    }
+
+    /**
+     * Share an already opened port
+     * @param port          port to use
+     * @return the new bus
+     */
+    pub fn new(port: Box<dyn MaestroPort>) -> MaestroBus {
+        MaestroBus {
+            port: Arc::new(Mutex::new(port))
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Box<dyn MaestroPort>> {
+        self.port.lock().unwrap()
+    }
+
+    /**
+     * Get a Maestro connected to this bus
+     * @param settings      settings.device selects the board, settings.port is ignored
+     * @return the new Maestro's instance
+     */
+    pub fn maestro(&self, settings: MaestroSettings) -> Result<Maestro> {
+        Ok(Maestro {
+            bus: self.clone(),
+            device: settings.device,
+            timeout: settings.timeout,
+            protocol: settings.protocol,
+            crc: settings.crc,
+            min_target: settings.min_target,
+            max_target: settings.max_target,
+            ranges: BTreeMap::new(),
+            targets: BTreeMap::new(),
+            clock: system_clock(),
+        })
+    }
+
+    /**
+     * Find the Maestros connected to this bus by sending Get Errors to
+     * every device number
+     * @param settings      timeout and crc used while probing
+     * @return the device numbers which answered
+     * @note this clears the errors of the boards found
+     */
+    pub fn scan(&self, settings: &MaestroSettings) -> Result<Vec<u8>> {
+        let mut found = Vec::new();
+        for device in 0..128 {
+            let mut maestro = self.maestro(MaestroSettings {
+                port: String::new(),
+                device,
+                protocol: MaestroProtocol::Pololu,
+                ..*settings
+            })?;
+            match maestro.get_errors() {
+                Ok(_) => {
+                    info!("Found Maestro with device number {}", device);
+                    found.push(device);
+                },
+                Err(Error::Timeout) => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(found)
+    }
 }
 
 /**
  * Represent the Maestro board
  * Tested with a mini maestro 18
+ * min_target/max_target are the limits of channels without their own range
+ * clock: used to wait between two polls, see with_clock
  */
 pub struct Maestro {
-    port: Box<dyn SerialPort>,
+    bus: MaestroBus,
     device: u8,
+    timeout: Duration,
+    protocol: MaestroProtocol,
+    crc: bool,
     pub min_target: u16,
     pub max_target: u16,
+    ranges: BTreeMap<u8, ChannelRange>,
+    targets: BTreeMap<u8, u16>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Maestro {
     /**
      * Get a new maestro instance, with default settings
-     * port: /dev/ttyACM0,
+     * port: the first Maestro Command port, /dev/ttyACM0 if none is found
      * device: 0x0c
      * min_target: 2000
      * max_target: 10000
+     * timeout: 100 ms
+     * protocol: Pololu
+     * crc: false
      * @return the new Maestro's instance
      */
-    pub fn new() -> Maestro {
-        Maestro::new_with_settings(MaestroSettings {
-            port: String::from("/dev/ttyACM0"),
-            device: 0x0c,
-            min_target: 2000,
-            max_target: 10000,
-        })
+    pub fn new() -> Result<Maestro> {
+        let mut settings = MaestroSettings::default();
+        match find_port(&Sysfs::default(), MaestroPortKind::Command) {
+            Ok(port) => settings.port = port,
+            Err(err) => warn!("{}, trying {}", err, settings.port),
+        }
+        Maestro::new_with_settings(settings)
     }
 
     /**
@@ -47,55 +547,241 @@ impl Maestro {
      * @param settings      User settings
      * @return the new Maestro's instance
      */
-    pub fn new_with_settings(settings: MaestroSettings) -> Maestro {
-        // Configure the serial port
-        let mut port = serial::open(&settings.port).unwrap();
-        let port_settings: serial::PortSettings = serial::PortSettings {
-            baud_rate:     serial::Baud9600,
-            char_size:     serial::Bits8,
-            parity:        serial::ParityNone,
-            stop_bits:     serial::Stop1,
-            flow_control:  serial::FlowNone,
-        };
-        port.configure(&port_settings).ok().expect("Cannot configure Maestro");
+    pub fn new_with_settings(settings: MaestroSettings) -> Result<Maestro> {
+        MaestroBus::open(&settings)?.maestro(settings)
+    }
+
+    /**
+     * Get a new maestro instance on an already opened port
+     * @param port          Port to use, settings.port is ignored
+     * @param settings      User settings
+     * @return the new Maestro's instance
+     */
+    pub fn new_with_port(port: Box<dyn MaestroPort>, settings: MaestroSettings) -> Result<Maestro> {
+        MaestroBus::new(port).maestro(settings)
+    }
+
+    /**
+     * Use another clock to wait between two polls
+     */
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Maestro {
         Maestro {
-            port: Box::new(port),
-            device: settings.device,
-            min_target: settings.min_target,
-            max_target: settings.max_target,
+            clock,
+            ..self
+        }
+    }
+
+    /**
+     * @return the bus of the Maestro, to reach other boards on the same line
+     */
+    pub fn bus(&self) -> &MaestroBus {
+        &self.bus
+    }
+
+    /**
+     * @return the device number of the Maestro
+     */
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+
+    /**
+     * Change how long to wait for an answer of the Maestro, other
+     * Maestros of the bus keep their own timeout
+     * @param timeout       new timeout
+     * @return if the operation was successful
+     */
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /**
+     * @return how long to wait for an answer of the Maestro
+     */
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /**
+     * Build a maestro command
+     * The pkt will be [0xaa, device, data...] or [data[0] | 0x80, data[1..]]
+     * depending on the protocol, followed by a CRC-7 if enabled
+     * @param data      command (Pololu command byte) and its data
+     * @return the packet to send
+     */
+    fn packet(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut buf = match self.protocol {
+            MaestroProtocol::Pololu => vec![0xaa, self.device, data[0]],
+            MaestroProtocol::Compact => vec![data[0] | 0x80],
+            MaestroProtocol::MiniSsc { .. } => return Err(Error::Unsupported(
+                format!("command {:#x} in Mini SSC protocol", data[0]))),
+        };
+        buf.extend_from_slice(&data[1..]);
+        if self.crc {
+            buf.push(crc7(&buf));
         }
+        Ok(buf)
     }
 
     /**
      * Send a maestro command via serial
-     * The pkt will be [0xaa, device, data...]
-     * @param data      buffer to send
+     * @param data      command (Pololu command byte) and its data
+     * @return if the operation was successful
+     */
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        let buf = self.packet(data)?;
+        self.write(&buf)
+    }
+
+    /**
+     * Write a packet on the serial port
+     * @param buf       packet to send
+     * @return if the operation was successful
+     */
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.bus.lock().write_all(buf).map_err(serial::Error::from)?;
+        Ok(())
+    }
+
+    /**
+     * @return the protocol used to talk to the board
+     */
+    pub fn protocol(&self) -> MaestroProtocol {
+        self.protocol
+    }
+
+    /**
+     * Send a command and read its answer. Bytes received before the
+     * command are dropped, so a late or stray byte can't shift the answer.
+     * @param data      command to send
+     * @param buf       buffer to fill with the answer
      * @return if the operation was successful
      */
-    fn send(&mut self, data: &mut Vec<u8>) -> bool {
-        let mut buf = vec![0xaa, self.device];
-        buf.append(data);
-        self.port.write(&buf[..]).is_ok()
+    fn query(&mut self, data: &[u8], buf: &mut [u8]) -> Result<()> {
+        let packet = self.packet(data)?;
+        // Keep the bus locked, so another Maestro's answer can't be read
+        let mut port = self.bus.lock();
+        if port.timeout() != self.timeout {
+            port.set_timeout(self.timeout)?;
+        }
+        port.clear_input()?;
+        port.write_all(&packet).map_err(serial::Error::from)?;
+        receive(&mut **port, buf)
+    }
+
+    /**
+     * @param channel      channel to read
+     * @return the software limits of the channel
+     */
+    pub fn channel_range(&self, channel: u8) -> ChannelRange {
+        self.ranges.get(&channel).cloned()
+            .unwrap_or_else(|| ChannelRange::new(self.min_target, self.max_target))
+    }
+
+    /**
+     * Change the software limits of a channel
+     * @param channel      channel to configure
+     * @param range        new limits
+     */
+    pub fn set_channel_range(&mut self, channel: u8, range: ChannelRange) {
+        self.ranges.insert(channel, range);
+    }
+
+    /**
+     * Change the lowest and highest targets of a channel, like setRange
+     * in maestro.py. The home is kept if it is still in the range.
+     * @param channel      channel to configure
+     * @param min_target   lowest target in quarter-µs
+     * @param max_target   highest target in quarter-µs
+     */
+    pub fn set_range(&mut self, channel: u8, min_target: u16, max_target: u16) {
+        let mut range = self.channel_range(channel);
+        range.min_target = min_target;
+        range.max_target = max_target;
+        if range.clamp(range.home) != range.home {
+            range.home = ChannelRange::new(min_target, max_target).home;
+        }
+        self.set_channel_range(channel, range);
+    }
+
+    /**
+     * Change the home position of a channel
+     * @param channel      channel to configure
+     * @param home         home target in quarter-µs
+     */
+    pub fn set_home(&mut self, channel: u8, home: u16) {
+        let mut range = self.channel_range(channel);
+        range.home = range.clamp(home);
+        self.set_channel_range(channel, range);
+    }
+
+    /**
+     * Move a channel to its home position
+     * @param channel      channel to move
+     * @return if the operation was successful
+     * @note unlike go_home, this uses the home stored in software
+     */
+    pub fn return_home(&mut self, channel: u8) -> Result<()> {
+        let home = self.channel_range(channel).home;
+        self.set_target(channel, home)
     }
 
     /**
      * Change a channel target
      * @param channel      channel to configure
-     * @param target       wanted target between min_target and max_target
+     * @param target       wanted target in quarter-µs, clamped in the channel range
      * @return if the operation was successful
      */
-    pub fn set_target(&mut self, channel: u8, target: u16) -> bool {
-        let mut target = target;
-        if target < self.min_target {
-            target = self.min_target;
-        }
-        if target > self.max_target {
-            target = self.max_target;
+    pub fn set_target(&mut self, channel: u8, target: u16) -> Result<()> {
+        let target = self.channel_range(channel).clamp(target);
+        if let MaestroProtocol::MiniSsc { offset } = self.protocol {
+            let max_target = self.max_target.max(self.min_target);
+            let range = (max_target - self.min_target).max(1) as u32;
+            let value = (target.clamp(self.min_target, max_target) - self.min_target) as u32 * 254 / range;
+            self.write(&[0xff, channel.saturating_add(offset), value as u8])?;
+            // Remember what the byte sent stands for, not the wanted target
+            self.targets.insert(channel, self.min_target + (value * range / 254) as u16);
+            return Ok(());
         }
         let lsb = (target & 0x7f) as u8;
         let msb = (target >> 7 & 0x7f) as u8;
-        let mut data = vec![0x04, channel, lsb, msb];
-        self.send(&mut data)
+        self.send(&[0x04, channel, lsb, msb])?;
+        self.targets.insert(channel, target);
+        Ok(())
+    }
+
+    /**
+     * @param channel      channel to read
+     * @return the last target sent to the channel, None if the channel
+     * wasn't commanded or was sent home by go_home. In Mini SSC protocol,
+     * the target is rounded down to the 255 steps of the packet.
+     */
+    pub fn commanded_target(&self, channel: u8) -> Option<u16> {
+        self.targets.get(&channel).cloned()
+    }
+
+    /**
+     * Change a channel target
+     * @param channel      channel to configure
+     * @param value        wanted target
+     * @param unit         unit of value
+     * @return if the operation was successful
+     */
+    pub fn set_target_in(&mut self, channel: u8, value: f32, unit: TargetUnit) -> Result<()> {
+        let target = self.channel_range(channel).to_target(value, unit);
+        self.set_target(channel, target)
+    }
+
+    /**
+     * Get the current position of a channel
+     * @param channel      channel to read
+     * @param unit         wanted unit
+     * @return the position in unit
+     */
+    pub fn get_target_in(&mut self, channel: u8, unit: TargetUnit) -> Result<f32> {
+        let target = self.get_target(channel)?;
+        Ok(self.channel_range(channel).from_target(target, unit))
     }
 
     /**
@@ -104,11 +790,10 @@ impl Maestro {
      * @param speed        wanted speed
      * @return if the operation was successful
      */
-    pub fn set_speed(&mut self, channel: u8, speed: u16) -> bool {
+    pub fn set_speed(&mut self, channel: u8, speed: u16) -> Result<()> {
         let lsb = (speed & 0x7f) as u8;
         let msb = (speed >> 7 & 0x7f) as u8;
-        let mut data = vec![0x07, channel, lsb, msb];
-        self.send(&mut data)
+        self.send(&[0x07, channel, lsb, msb])
     }
 
     /**
@@ -117,38 +802,545 @@ impl Maestro {
      * @param accel        wanted accel
      * @return if the operation was successful
      */
-    pub fn set_accel(&mut self, channel: u8, accel: u16) -> bool {
+    pub fn set_accel(&mut self, channel: u8, accel: u16) -> Result<()> {
         let lsb = (accel & 0x7f) as u8;
         let msb = (accel >> 7 & 0x7f) as u8;
-        let mut data = vec![0x09, channel, lsb, msb];
-        self.send(&mut data)
+        self.send(&[0x09, channel, lsb, msb])
     }
 
     /**
-     * Get current position in servo_range
+     * Get current position in quarter-µs
      * @param channel       Channel to read
      * @return the position read on the socket
      * @note a difference will exists between real value and what the Maestro send
      */
-    pub fn get_target(&mut self, channel: u8) -> u16 {
-        let mut data = vec![0x10, channel];
-        self.send(&mut data);
-        let mut buf = vec![0; 2];
-        self.port.read(&mut buf[..]).ok().expect("Couldn't read on serial socket");
+    pub fn get_target(&mut self, channel: u8) -> Result<u16> {
+        let mut buf = [0; 2];
+        self.query(&[0x10, channel], &mut buf)?;
         let res = ((buf[1] as u16 & 0x00ff) << 8) + buf[0] as u16;
-        println!("... {}", res);
-        return res;
+        debug!("Position for channel {}: {}", channel, res);
+        Ok(res)
     }
 
     /**
      * Get if all servos reached their target
      * @return if all servos reached their target
+     * @note not available on the Micro Maestro, see is_channel_moving
      */
-    pub fn is_moving(&mut self) -> bool {
-        let mut data = vec![0x13];
-        self.send(&mut data);
-        let mut buf = vec![0; 1];
-        self.port.read(&mut buf[..]).ok().expect("Couldn't read on serial socket");
-        return buf[0] != 0;
+    pub fn is_moving(&mut self) -> Result<bool> {
+        let mut buf = [0; 1];
+        self.query(&[0x13], &mut buf)?;
+        Ok(buf[0] != 0)
+    }
+
+    /**
+     * Get if a servo is still going to the last target sent, by comparing
+     * its position with that target like isMoving in maestro.py
+     * @param channel      channel to read
+     * @return if the servo didn't reach its target, false if no target was
+     * sent, Error::Unsupported in Mini SSC protocol
+     */
+    pub fn is_channel_moving(&mut self, channel: u8) -> Result<bool> {
+        if let MaestroProtocol::MiniSsc { .. } = self.protocol {
+            return Err(Error::Unsupported(String::from("reading positions in Mini SSC protocol")));
+        }
+        match self.commanded_target(channel) {
+            Some(target) => Ok(self.get_target(channel)? != target),
+            None => Ok(false),
+        }
     }
-}
\ No newline at end of file
+
+    /**
+     * Block until servos reach their targets
+     * @param channels     channels to wait for
+     * @param timeout      maximum time to wait
+     * @return if the operation was successful, Error::Timeout if a servo
+     * is still moving after timeout, Error::Unsupported in Mini SSC protocol
+     */
+    pub fn wait_until_settled(&mut self, channels: &[u8], timeout: Duration) -> Result<()> {
+        let start = self.clock.now();
+        loop {
+            let mut moving = false;
+            for &channel in channels {
+                if self.is_channel_moving(channel)? {
+                    moving = true;
+                    break;
+                }
+            }
+            if !moving {
+                return Ok(());
+            }
+            if self.clock.now() - start >= timeout {
+                return Err(Error::Timeout);
+            }
+            // The Maestro updates its outputs every 10 ms
+            self.clock.sleep(Duration::from_millis(10));
+        }
+    }
+
+    /**
+     * Change the target of several consecutive channels at once
+     * @param first_channel     first channel to configure
+     * @param targets           wanted targets for first_channel, first_channel + 1...
+     * @return if the operation was successful, Error::InvalidServoGroup if
+     * the channels go past channel 127
+     * @note not available on the Micro Maestro, sent as one packet per
+     * channel in Mini SSC protocol
+     */
+    pub fn set_targets(&mut self, first_channel: u8, targets: &[u16]) -> Result<()> {
+        if first_channel as usize + targets.len() > 128 {
+            return Err(Error::InvalidServoGroup(format!("{} targets from channel {}, the Maestro has at most 128 channels",
+                targets.len(), first_channel)));
+        }
+        if let MaestroProtocol::MiniSsc { .. } = self.protocol {
+            for (channel, &target) in (first_channel..).zip(targets) {
+                self.set_target(channel, target)?;
+            }
+            return Ok(());
+        }
+        let targets: Vec<(u8, u16)> = (first_channel..).zip(targets)
+            .map(|(channel, &target)| (channel, self.channel_range(channel).clamp(target)))
+            .collect();
+        let mut data = vec![0x1f, targets.len() as u8, first_channel];
+        for &(_, target) in targets.iter() {
+            data.push((target & 0x7f) as u8);
+            data.push((target >> 7 & 0x7f) as u8);
+        }
+        self.send(&data)?;
+        self.targets.extend(targets);
+        Ok(())
+    }
+
+    /**
+     * Get and clear the errors of the board
+     * @return the errors reported
+     */
+    pub fn get_errors(&mut self) -> Result<MaestroErrors> {
+        let mut buf = [0; 2];
+        self.query(&[0x21], &mut buf)?;
+        Ok(MaestroErrors(((buf[1] as u16) << 8) + buf[0] as u16))
+    }
+
+    /**
+     * Send all servos to their home position
+     * @return if the operation was successful
+     */
+    pub fn go_home(&mut self) -> Result<()> {
+        self.send(&[0x22])?;
+        // Home positions are configured on the board, targets are unknown
+        self.targets.clear();
+        Ok(())
+    }
+
+    /**
+     * Stop the current Maestro script
+     * @return if the operation was successful
+     */
+    pub fn stop_script(&mut self) -> Result<()> {
+        self.send(&[0x24])
+    }
+
+    /**
+     * Run a subroutine of the current Maestro script
+     * @param subroutine      subroutine number
+     * @return if the operation was successful
+     */
+    pub fn restart_script(&mut self, subroutine: u8) -> Result<()> {
+        self.send(&[0x27, subroutine])
+    }
+
+    /**
+     * Run a subroutine of the current Maestro script, with a parameter
+     * pushed on the stack
+     * @param subroutine      subroutine number
+     * @param parameter       parameter between 0 and 16383
+     * @return if the operation was successful
+     */
+    pub fn restart_script_with_parameter(&mut self, subroutine: u8, parameter: u16) -> Result<()> {
+        let lsb = (parameter & 0x7f) as u8;
+        let msb = (parameter >> 7 & 0x7f) as u8;
+        self.send(&[0x28, subroutine, lsb, msb])
+    }
+
+    /**
+     * Get if the script is running
+     * @return the script status
+     */
+    pub fn get_script_status(&mut self) -> Result<ScriptStatus> {
+        let mut buf = [0; 1];
+        self.query(&[0x2e], &mut buf)?;
+        match buf[0] {
+            0 => Ok(ScriptStatus::Running),
+            1 => Ok(ScriptStatus::Stopped),
+            status => Err(Error::Protocol(format!("unknown script status {}", status))),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::emulator::{MaestroChain, MaestroEmulator};
+
+    /**
+     * Port keeping every byte written, and answering the same bytes to
+     * every packet
+     */
+    struct RecordingPort {
+        written: Arc<Mutex<Vec<u8>>>,
+        answer: Vec<u8>,
+        pending: Vec<u8>,
+    }
+
+    impl Read for RecordingPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "nothing to read"));
+            }
+            let len = buf.len().min(self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for RecordingPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            self.pending.extend_from_slice(&self.answer);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MaestroPort for RecordingPort {
+        fn timeout(&self) -> Duration {
+            Duration::from_millis(0)
+        }
+
+        fn set_timeout(&mut self, _: Duration) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn recording_maestro(protocol: MaestroProtocol) -> (Maestro, Arc<Mutex<Vec<u8>>>) {
+        answering_maestro(protocol, &[])
+    }
+
+    fn answering_maestro(protocol: MaestroProtocol, answer: &[u8]) -> (Maestro, Arc<Mutex<Vec<u8>>>) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let port = RecordingPort {
+            written: written.clone(),
+            answer: answer.to_vec(),
+            pending: Vec::new(),
+        };
+        let settings = MaestroSettings {
+            protocol,
+            crc: true,
+            ..MaestroSettings::default()
+        };
+        (Maestro::new_with_port(Box::new(port), settings).unwrap(), written)
+    }
+
+    #[test]
+    fn script_commands() {
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::Pololu);
+        maestro.stop_script().unwrap();
+        maestro.restart_script(3).unwrap();
+        // 14 bits, split in two 7-bit bytes
+        maestro.restart_script_with_parameter(3, 0x3fff).unwrap();
+        maestro.restart_script_with_parameter(4, 200).unwrap();
+        assert_eq!(*written.lock().unwrap(), [
+            0xaa, 0x0c, 0x24, crc7(&[0xaa, 0x0c, 0x24]),
+            0xaa, 0x0c, 0x27, 0x03, crc7(&[0xaa, 0x0c, 0x27, 0x03]),
+            0xaa, 0x0c, 0x28, 0x03, 0x7f, 0x7f, crc7(&[0xaa, 0x0c, 0x28, 0x03, 0x7f, 0x7f]),
+            0xaa, 0x0c, 0x28, 0x04, 0x48, 0x01, crc7(&[0xaa, 0x0c, 0x28, 0x04, 0x48, 0x01]),
+        ]);
+
+        let (mut maestro, written) = answering_maestro(MaestroProtocol::Compact, &[0]);
+        assert_eq!(maestro.get_script_status().unwrap(), ScriptStatus::Running);
+        assert_eq!(*written.lock().unwrap(), [0xae, crc7(&[0xae])]);
+        let (mut maestro, _) = answering_maestro(MaestroProtocol::Compact, &[1]);
+        assert_eq!(maestro.get_script_status().unwrap(), ScriptStatus::Stopped);
+        let (mut maestro, _) = answering_maestro(MaestroProtocol::Compact, &[2]);
+        assert!(matches!(maestro.get_script_status(), Err(Error::Protocol(_))));
+        let (mut maestro, _) = recording_maestro(MaestroProtocol::Compact);
+        assert!(matches!(maestro.get_script_status(), Err(Error::Timeout)));
+
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        let mut maestro = Maestro::new_with_port(Box::new(emulator.clone()), MaestroSettings::default()).unwrap();
+        maestro.restart_script_with_parameter(0, 1000).unwrap();
+        assert_eq!(maestro.get_script_status().unwrap(), ScriptStatus::Running);
+        maestro.stop_script().unwrap();
+        assert_eq!(maestro.get_script_status().unwrap(), ScriptStatus::Stopped);
+    }
+
+    #[test]
+    fn errors_are_decoded() {
+        let errors = MaestroErrors(MaestroErrors::SERIAL_SIGNAL.0 | MaestroErrors::SERIAL_PROTOCOL.0);
+        assert!(errors.contains(MaestroErrors::SERIAL_SIGNAL));
+        assert!(!errors.contains(MaestroErrors::SERIAL_CRC));
+        assert!(!errors.contains(MaestroErrors(MaestroErrors::SERIAL_SIGNAL.0 | MaestroErrors::SERIAL_CRC.0)));
+        assert!(MaestroErrors::default().is_empty());
+        assert!(MaestroErrors::default().names().is_empty());
+
+        // Both bytes of the error word
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        emulator.raise_errors(MaestroErrors(0x0111));
+        let mut maestro = Maestro::new_with_port(Box::new(emulator.clone()), MaestroSettings::default()).unwrap();
+        let errors = maestro.get_errors().unwrap();
+        assert_eq!(errors, MaestroErrors(0x0111));
+        assert_eq!(errors.names(), ["serial signal", "serial protocol", "script program counter"]);
+        assert!(maestro.get_errors().unwrap().is_empty());
+
+        let (mut maestro, _) = answering_maestro(MaestroProtocol::Pololu, &[0x28, 0x01]);
+        assert_eq!(maestro.get_errors().unwrap().names(), ["serial CRC", "serial timeout", "script program counter"]);
+    }
+
+    #[test]
+    fn multiple_targets_stay_in_128_channels() {
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::Pololu);
+        maestro.set_targets(126, &[6000, 6000]).unwrap();
+        assert_eq!(written.lock().unwrap()[3..5], [2, 126]);
+        written.lock().unwrap().clear();
+        assert!(matches!(maestro.set_targets(127, &[6000, 6000]), Err(Error::InvalidServoGroup(_))));
+        assert!(matches!(maestro.set_targets(255, &[6000]), Err(Error::InvalidServoGroup(_))));
+        assert!(matches!(maestro.set_targets(0, &[6000; 256]), Err(Error::InvalidServoGroup(_))));
+        assert!(written.lock().unwrap().is_empty());
+        assert_eq!(maestro.commanded_target(0), None);
+
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::MiniSsc { offset: 0 });
+        assert!(matches!(maestro.set_targets(200, &[6000]), Err(Error::InvalidServoGroup(_))));
+        assert!(written.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stray_bytes_and_silent_boards() {
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        let mut maestro = Maestro::new_with_port(Box::new(emulator.clone()), MaestroSettings::default()).unwrap();
+        maestro.set_target(0, 7000).unwrap();
+        emulator.advance(Duration::from_secs(1));
+        // Noise received before the question is dropped
+        emulator.inject_output(&[0x42]);
+        assert_eq!(maestro.get_target(0).unwrap(), 7000);
+        emulator.inject_output(&[0x42, 0x43, 0x44]);
+        assert!(!maestro.is_moving().unwrap());
+
+        emulator.set_unresponsive(true);
+        assert!(matches!(maestro.get_target(0), Err(Error::Timeout)));
+        assert!(matches!(maestro.get_errors(), Err(Error::Timeout)));
+        emulator.set_unresponsive(false);
+        assert_eq!(maestro.get_target(0).unwrap(), 7000);
+    }
+
+    #[test]
+    fn mini_ssc_survives_inverted_limits() {
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::MiniSsc { offset: 0 });
+        maestro.min_target = 7000;
+        maestro.max_target = 5000;
+        maestro.set_target(0, 6000).unwrap();
+        maestro.set_target(1, 9000).unwrap();
+        assert_eq!(*written.lock().unwrap(), [0xff, 0x00, 0x00, 0xff, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn mini_ssc_remembers_what_was_sent() {
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::MiniSsc { offset: 0 });
+        maestro.set_target(0, 6001).unwrap();
+        maestro.set_target(1, 10000).unwrap();
+        assert_eq!(*written.lock().unwrap(), [0xff, 0x00, 0x7f, 0xff, 0x01, 0xfe]);
+        // 127 steps of 8000 / 254
+        assert_eq!(maestro.commanded_target(0), Some(6000));
+        assert_eq!(maestro.commanded_target(1), Some(10000));
+        assert!(matches!(maestro.is_channel_moving(0), Err(Error::Unsupported(_))));
+        assert!(matches!(maestro.is_channel_moving(2), Err(Error::Unsupported(_))));
+        assert!(matches!(maestro.wait_until_settled(&[0], Duration::from_secs(1)), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn crc7_matches_reference() {
+        // Example from the Pololu documentation
+        assert_eq!(crc7(&[0x83, 0x01]), 0x17);
+        assert_eq!(crc7(&[]), 0x00);
+        assert_eq!(crc7(&[0xaa, 0x0c, 0x04, 0x00, 0x70, 0x2e]), 0x22);
+    }
+
+    #[test]
+    fn crc_is_appended_to_packets() {
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::Pololu);
+        maestro.set_target(0, 6000).unwrap();
+        assert_eq!(*written.lock().unwrap(), [0xaa, 0x0c, 0x04, 0x00, 0x70, 0x2e, 0x22]);
+
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::Compact);
+        maestro.set_target(0, 6000).unwrap();
+        maestro.go_home().unwrap();
+        assert_eq!(*written.lock().unwrap(), [0x84, 0x00, 0x70, 0x2e, 0x2b, 0xa2, crc7(&[0xa2])]);
+
+        let (mut maestro, written) = recording_maestro(MaestroProtocol::MiniSsc { offset: 0 });
+        maestro.set_target(1, 10000).unwrap();
+        assert_eq!(*written.lock().unwrap(), [0xff, 0x01, 0xfe]);
+    }
+
+    #[test]
+    fn crc_is_checked_by_the_board() {
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        emulator.set_crc(true);
+        let settings = MaestroSettings {
+            crc: true,
+            ..MaestroSettings::default()
+        };
+        let mut maestro = Maestro::new_with_port(Box::new(emulator.clone()), settings).unwrap();
+        maestro.set_target(2, 7000).unwrap();
+        assert_eq!(emulator.channel(2).unwrap().target, 7000);
+        assert!(maestro.get_errors().unwrap().is_empty());
+
+        let mut port = emulator.clone();
+        port.write_all(&[0xaa, 0x0c, 0x04, 0x02, 0x70, 0x2e, 0x00]).unwrap();
+        assert_eq!(emulator.channel(2).unwrap().target, 7000);
+        assert!(maestro.get_errors().unwrap().contains(MaestroErrors::SERIAL_CRC));
+    }
+
+    #[test]
+    fn chained_boards_share_the_bus() {
+        let chain = MaestroChain::new(vec![MaestroEmulator::new(0x0c, 12), MaestroEmulator::new(0x0d, 12)]);
+        let bus = MaestroBus::new(Box::new(chain.clone()));
+        assert_eq!(bus.scan(&MaestroSettings::default()).unwrap(), [0x0c, 0x0d]);
+
+        let mut first = bus.maestro(MaestroSettings::default()).unwrap();
+        let mut second = bus.maestro(MaestroSettings { device: 0x0d, ..MaestroSettings::default() }).unwrap();
+        first.set_target(3, 5000).unwrap();
+        second.set_target(3, 7000).unwrap();
+        assert_eq!(chain.boards()[0].channel(3).unwrap().target, 5000);
+        assert_eq!(chain.boards()[1].channel(3).unwrap().target, 7000);
+        assert_eq!(second.get_target(3).unwrap(), 6000);
+    }
+
+    #[test]
+    fn each_maestro_keeps_its_timeout() {
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        emulator.clone().set_timeout(Duration::from_secs(1)).unwrap();
+        let bus = MaestroBus::new(Box::new(emulator.clone()));
+        let mut fast = bus.maestro(MaestroSettings { timeout: Duration::from_millis(10), ..MaestroSettings::default() }).unwrap();
+        let mut slow = bus.maestro(MaestroSettings { timeout: Duration::from_millis(500), ..MaestroSettings::default() }).unwrap();
+        assert_eq!(emulator.timeout(), Duration::from_secs(1));
+        assert_eq!((fast.timeout(), slow.timeout()), (Duration::from_millis(10), Duration::from_millis(500)));
+
+        fast.get_errors().unwrap();
+        assert_eq!(emulator.timeout(), Duration::from_millis(10));
+        slow.get_errors().unwrap();
+        assert_eq!(emulator.timeout(), Duration::from_millis(500));
+        fast.set_timeout(Duration::from_millis(20)).unwrap();
+        assert_eq!(emulator.timeout(), Duration::from_millis(500));
+        fast.get_target(0).unwrap();
+        assert_eq!(emulator.timeout(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn channel_ranges_and_units() {
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        let mut maestro = Maestro::new_with_port(Box::new(emulator.clone()), MaestroSettings::default()).unwrap();
+        maestro.set_range(1, 4000, 8000);
+        maestro.set_target(1, 9000).unwrap();
+        assert_eq!(emulator.channel(1).unwrap().target, 8000);
+        maestro.set_target(0, 9000).unwrap();
+        assert_eq!(emulator.channel(0).unwrap().target, 9000);
+
+        maestro.set_target_in(1, 90.0, TargetUnit::Degrees).unwrap();
+        assert_eq!(emulator.channel(1).unwrap().target, 6000);
+        maestro.set_target_in(1, 1250.0, TargetUnit::Micros).unwrap();
+        assert_eq!(emulator.channel(1).unwrap().target, 5000);
+
+        maestro.set_home(1, 4400);
+        maestro.return_home(1).unwrap();
+        assert_eq!(emulator.channel(1).unwrap().target, 4400);
+        emulator.advance(Duration::from_secs(1));
+        assert_eq!(maestro.get_target_in(1, TargetUnit::Micros).unwrap(), 1100.0);
+        assert_eq!(maestro.get_target_in(1, TargetUnit::Degrees).unwrap(), 18.0);
+    }
+
+    #[test]
+    fn channels_settle_on_their_commanded_targets() {
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        let clock = ManualClock::new();
+        let mut maestro = Maestro::new_with_port(Box::new(emulator.clone()), MaestroSettings::default()).unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        assert!(!maestro.is_channel_moving(0).unwrap());
+        maestro.set_speed(0, 100).unwrap();
+        maestro.set_targets(0, &[7000, 5000]).unwrap();
+        assert!(maestro.is_channel_moving(0).unwrap());
+        assert!(matches!(maestro.wait_until_settled(&[0, 1], Duration::from_millis(0)), Err(Error::Timeout)));
+        assert_eq!(clock.now(), Duration::from_millis(0));
+
+        // The board moves by one frame per poll, as long as the clock sleeps
+        emulator.set_auto_advance(Duration::from_millis(10));
+        maestro.wait_until_settled(&[0, 1], Duration::from_secs(1)).unwrap();
+        assert_eq!(maestro.get_target(0).unwrap(), 7000);
+        assert_eq!(clock.now(), Duration::from_millis(100));
+
+        maestro.set_speed(0, 1).unwrap();
+        maestro.set_target(0, 6000).unwrap();
+        let start = clock.now();
+        assert!(matches!(maestro.wait_until_settled(&[0], Duration::from_millis(200)), Err(Error::Timeout)));
+        assert_eq!(clock.now() - start, Duration::from_millis(200));
+    }
+
+    #[test]
+    fn ports_are_found_in_sysfs() {
+        let root = std::env::temp_dir().join(format!("cucaracha-tty-{}", std::process::id()));
+        let usb = root.join("sys/devices/usb1/1-1");
+        let add_tty = |name: &str, device: &std::path::Path, interface: u8| {
+            let interface_dir = device.join(format!("1-1:1.{}", interface));
+            std::fs::create_dir_all(&interface_dir).unwrap();
+            std::fs::write(interface_dir.join("bInterfaceNumber"), format!("{:02}\n", interface)).unwrap();
+            let tty = root.join("sys/class/tty").join(name);
+            std::fs::create_dir_all(&tty).unwrap();
+            std::os::unix::fs::symlink(&interface_dir, tty.join("device")).unwrap();
+        };
+        add_tty("ttyACM0", &root.join("sys/devices/usb1/1-2"), 0);
+        add_tty("ttyACM1", &usb, 0);
+        add_tty("ttyACM2", &usb, 2);
+        std::fs::create_dir_all(root.join("sys/class/tty/ttyS0")).unwrap();
+        std::fs::write(root.join("sys/devices/usb1/1-2/idVendor"), "2341\n").unwrap();
+        std::fs::write(usb.join("idVendor"), "1ffb\n").unwrap();
+        std::fs::write(usb.join("idProduct"), "008a\n").unwrap();
+        std::fs::write(usb.join("serial"), "00012345\n").unwrap();
+
+        let fs = Sysfs::new(&root);
+        let ports = find_ports(&fs);
+        let command = find_port(&fs, MaestroPortKind::Command);
+        let ttl = find_port(&fs, MaestroPortKind::Ttl);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(ports.unwrap(), [
+            MaestroPortInfo {
+                path: String::from("/dev/ttyACM1"),
+                kind: MaestroPortKind::Command,
+                product: 0x8a,
+                serial: Some(String::from("00012345")),
+            },
+            MaestroPortInfo {
+                path: String::from("/dev/ttyACM2"),
+                kind: MaestroPortKind::Ttl,
+                product: 0x8a,
+                serial: Some(String::from("00012345")),
+            },
+        ]);
+        assert_eq!(command.unwrap(), "/dev/ttyACM1");
+        assert_eq!(ttl.unwrap(), "/dev/ttyACM2");
+    }
+
+    #[test]
+    fn baud_rate_detection_needs_an_answer() {
+        let emulator = MaestroEmulator::new(0x0c, 6);
+        let bus = MaestroBus::new(Box::new(emulator.clone()));
+        bus.detect_baud(&MaestroSettings::default()).unwrap();
+        let mut maestro = bus.maestro(MaestroSettings::default()).unwrap();
+        assert!(maestro.get_errors().unwrap().is_empty());
+
+        emulator.set_unresponsive(true);
+        assert!(matches!(bus.detect_baud(&MaestroSettings::default()), Err(Error::Protocol(_))));
+    }
+}
@@ -0,0 +1,596 @@
+use crate::clock::*;
+use crate::error::*;
+use crate::hal::*;
+use crate::pwmled::*;
+use crate::rgbled::*;
+use crate::servo::*;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/**
+ * Progress of a motion over time
+ * Linear: constant speed
+ * EaseIn/EaseOut/EaseInOut: quadratic acceleration at the start, the end or both
+ * Cubic: cubic acceleration and deceleration
+ * Sine: sinusoidal acceleration and deceleration
+ * Trapezoidal: constant acceleration during accel (ratio of the duration,
+ * up to 0.5), constant speed, then constant deceleration
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    Cubic,
+    Sine,
+    Trapezoidal { accel: f32 },
+}
+
+impl Easing {
+    /**
+     * @param t         elapsed ratio of the duration, between 0 and 1
+     * @return the progress between 0 and 1, 0 at t = 0 and 1 at t = 1
+     */
+    pub fn progress(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match *self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2.0 - t),
+            Easing::EaseInOut if t < 0.5 => 2.0 * t * t,
+            Easing::EaseInOut => 1.0 - 2.0 * (1.0 - t) * (1.0 - t),
+            Easing::Cubic if t < 0.5 => 4.0 * t * t * t,
+            Easing::Cubic => 1.0 - 4.0 * (1.0 - t).powi(3),
+            Easing::Sine => (1.0 - (t * std::f32::consts::PI).cos()) / 2.0,
+            Easing::Trapezoidal { accel } => {
+                let accel = accel.clamp(0.0, 0.5);
+                if accel == 0.0 {
+                    return t;
+                }
+                let speed = 1.0 / (1.0 - accel);
+                if t < accel {
+                    speed * t * t / (2.0 * accel)
+                } else if t <= 1.0 - accel {
+                    speed * (t - accel / 2.0)
+                } else {
+                    1.0 - speed * (1.0 - t) * (1.0 - t) / (2.0 * accel)
+                }
+            },
+        }
+    }
+}
+
+/**
+ * Run a blocking transition: apply is called with the eased progress at
+ * fixed deadlines from the start, so the time spent in apply doesn't add
+ * up, and the last call is made with 1 at duration
+ * @param clock             clock used to wait
+ * @param duration          time of the transition
+ * @param update_period     time between two calls
+ * @param easing            progress curve
+ * @param apply             called with the progress between 0 and 1
+ * @return if the operation was successful
+ */
+pub fn run_transition<F>(clock: &dyn Clock, duration: Duration, update_period: Duration, easing: Easing,
+    mut apply: F) -> Result<()>
+    where F: FnMut(f32) -> Result<()> {
+    let steps = match update_period.as_nanos() {
+        0 => 1,
+        period => (duration.as_nanos() / period).max(1) as u32,
+    };
+    let start = clock.now();
+    for step in 1..=steps {
+        clock.sleep_until(start + duration * step / steps);
+        let progress = match step {
+            step if step == steps => 1.0,
+            step => easing.progress(step as f32 / steps as f32),
+        };
+        apply(progress)?;
+    }
+    Ok(())
+}
+
+/**
+ * A value which can be moved progressively
+ */
+pub trait Interpolate: Copy + Send + 'static {
+    /**
+     * @param to        end value
+     * @param ratio     progress between 0 and 1
+     * @return the value between self and to
+     */
+    fn interpolate(self, to: Self, ratio: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(self, to: f32, ratio: f32) -> f32 {
+        self + (to - self) * ratio
+    }
+}
+
+impl Interpolate for (f32, f32, f32) {
+    fn interpolate(self, to: (f32, f32, f32), ratio: f32) -> (f32, f32, f32) {
+        (self.0.interpolate(to.0, ratio), self.1.interpolate(to.1, ratio), self.2.interpolate(to.2, ratio))
+    }
+}
+
+/**
+ * Something the scheduler can move: a servo position, a LED luminosity...
+ */
+pub trait Movable: Send + 'static {
+    type Value: Interpolate;
+
+    /**
+     * @return the current value
+     */
+    fn current(&mut self) -> Result<Self::Value>;
+
+    /**
+     * Change the value
+     * @param value     wanted value
+     * @return if the operation was successful
+     */
+    fn apply(&mut self, value: Self::Value) -> Result<()>;
+}
+
+impl<D: ServoDriver + Send + 'static> Movable for Servo<D> {
+    type Value = f32;
+
+    fn current(&mut self) -> Result<f32> {
+        self.position()
+    }
+
+    fn apply(&mut self, position: f32) -> Result<()> {
+        self.set_position(position)
+    }
+}
+
+impl<P: PwmChannel + Send + 'static> Movable for PwmLed<P> {
+    type Value = f32;
+
+    fn current(&mut self) -> Result<f32> {
+        self.get_luminosity()
+    }
+
+    fn apply(&mut self, luminosity: f32) -> Result<()> {
+        self.set_luminosity(luminosity)
+    }
+}
+
+impl<P: PwmChannel + Send + 'static> Movable for RGBLed<P> {
+    type Value = (f32, f32, f32);
+
+    fn current(&mut self) -> Result<(f32, f32, f32)> {
+        Ok((self.r_led.get_luminosity()?, self.g_led.get_luminosity()?, self.b_led.get_luminosity()?))
+    }
+
+    fn apply(&mut self, color: (f32, f32, f32)) -> Result<()> {
+        self.set_color(color)
+    }
+}
+
+/**
+ * A transition run by the scheduler
+ */
+trait Motion: Send {
+    /**
+     * Move to the state expected at elapsed
+     * @param elapsed   time since the start of the motion
+     * @return if the motion is finished
+     */
+    fn update(&mut self, elapsed: Duration) -> Result<bool>;
+}
+
+/**
+ * Move a shared Movable from its current value to a target
+ */
+struct Transition<M: Movable> {
+    target: Arc<Mutex<M>>,
+    from: Option<M::Value>,
+    to: M::Value,
+    duration: Duration,
+    easing: Easing,
+}
+
+impl<M: Movable> Motion for Transition<M> {
+    fn update(&mut self, elapsed: Duration) -> Result<bool> {
+        let mut target = self.target.lock().unwrap();
+        let from = match self.from {
+            Some(from) => from,
+            None => *self.from.get_or_insert(target.current()?),
+        };
+        if elapsed >= self.duration {
+            target.apply(self.to)?;
+            return Ok(true);
+        }
+        let ratio = elapsed.as_secs_f32() / self.duration.as_secs_f32();
+        target.apply(from.interpolate(self.to, self.easing.progress(ratio)))?;
+        Ok(false)
+    }
+}
+
+#[derive(Debug)]
+enum Status {
+    Running,
+    Done,
+    Cancelled,
+    Failed(Error),
+}
+
+/**
+ * State shared by a MotionHandle and the scheduler
+ */
+#[derive(Debug)]
+struct MotionState {
+    status: Mutex<Status>,
+    finished: Condvar,
+}
+
+impl MotionState {
+    fn finish(&self, status: Status) {
+        let mut current = self.status.lock().unwrap();
+        if let Status::Running = *current {
+            *current = status;
+        }
+        self.finished.notify_all();
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(*self.status.lock().unwrap(), Status::Running)
+    }
+}
+
+/**
+ * Follow a motion started by a MotionScheduler
+ */
+#[derive(Debug)]
+pub struct MotionHandle {
+    state: Arc<MotionState>,
+}
+
+impl MotionHandle {
+    /**
+     * Block until the motion ends
+     * @return if the motion reached its target, the error raised while
+     * moving otherwise. A cancelled motion is not an error.
+     */
+    pub fn wait(self) -> Result<()> {
+        let mut status = self.state.status.lock().unwrap();
+        while let Status::Running = *status {
+            status = self.state.finished.wait(status).unwrap();
+        }
+        match std::mem::replace(&mut *status, Status::Done) {
+            Status::Failed(err) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Stop the motion where it is
+     */
+    pub fn cancel(&self) {
+        self.state.finish(Status::Cancelled);
+    }
+
+    /**
+     * @return if the motion ended, cancelled or failed
+     */
+    pub fn is_done(&self) -> bool {
+        !self.state.is_running()
+    }
+}
+
+struct Active {
+    /// Address of the moved object, a new motion replaces the previous one
+    key: usize,
+    motion: Box<dyn Motion>,
+    state: Arc<MotionState>,
+    start: Option<Duration>,
+}
+
+/**
+ * A background thread running every motion in one tick loop, so several
+ * servos and LEDs move at the same time without blocking the caller.
+ * Moved objects are shared with the scheduler through an Arc<Mutex<..>>.
+ */
+pub struct MotionScheduler {
+    sender: Sender<Active>,
+}
+
+impl MotionScheduler {
+    /**
+     * Start the scheduler thread
+     * @param tick      period between two updates
+     * @return the new scheduler
+     */
+    pub fn new(tick: Duration) -> MotionScheduler {
+        MotionScheduler::new_with_clock(tick, system_clock())
+    }
+
+    /**
+     * Start the scheduler thread on a given clock
+     * @param tick      period between two updates
+     * @param clock     clock used to wait between updates
+     * @return the new scheduler
+     */
+    pub fn new_with_clock(tick: Duration, clock: Arc<dyn Clock>) -> MotionScheduler {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || MotionScheduler::run(receiver, tick, clock));
+        MotionScheduler {
+            sender
+        }
+    }
+
+    fn run(receiver: Receiver<Active>, tick: Duration, clock: Arc<dyn Clock>) {
+        let mut motions: Vec<Active> = Vec::new();
+        let mut next_tick = clock.now();
+        loop {
+            // Sleep until the next tick, or until a motion arrives when idle
+            if motions.is_empty() {
+                match receiver.recv() {
+                    Ok(active) => motions.push(active),
+                    Err(_) => return,
+                }
+                next_tick = clock.now();
+            } else {
+                clock.sleep_until(next_tick);
+            }
+            while let Ok(active) = receiver.try_recv() {
+                for previous in motions.iter().filter(|previous| previous.key == active.key) {
+                    previous.state.finish(Status::Cancelled);
+                }
+                motions.push(active);
+            }
+
+            let now = clock.now();
+            for active in motions.iter_mut().filter(|active| active.state.is_running()) {
+                let start = *active.start.get_or_insert(now);
+                match active.motion.update(now - start) {
+                    Ok(true) => active.state.finish(Status::Done),
+                    Ok(false) => {},
+                    Err(err) => active.state.finish(Status::Failed(err)),
+                }
+            }
+            motions.retain(|active| active.state.is_running());
+            next_tick += tick;
+        }
+    }
+
+    fn start(&self, key: usize, motion: Box<dyn Motion>) -> MotionHandle {
+        let state = Arc::new(MotionState {
+            status: Mutex::new(Status::Running),
+            finished: Condvar::new(),
+        });
+        let active = Active {
+            key,
+            motion,
+            state: state.clone(),
+            start: None,
+        };
+        if self.sender.send(active).is_err() {
+            state.finish(Status::Failed(Error::Unsupported(String::from("the motion scheduler stopped"))));
+        }
+        MotionHandle {
+            state
+        }
+    }
+
+    /**
+     * Move anything Movable from its current value to a target
+     * @param target        object to move, a running motion on it is cancelled
+     * @param to            wanted value
+     * @param duration      time of the transition
+     * @param easing        progress curve
+     * @return the handle of the motion
+     */
+    pub fn move_to<M: Movable>(&self, target: &Arc<Mutex<M>>, to: M::Value, duration: Duration,
+        easing: Easing) -> MotionHandle {
+        let key = Arc::as_ptr(target) as *const () as usize;
+        self.start(key, Box::new(Transition {
+            target: target.clone(),
+            from: None,
+            to,
+            duration,
+            easing,
+        }))
+    }
+
+    /**
+     * Move a servo, like Servo::go_to without blocking
+     * @param servo         servo to move
+     * @param position      wanted position
+     * @param duration      time of the transition
+     * @param easing        progress curve
+     * @return the handle of the motion
+     */
+    pub fn go_to<D: ServoDriver + Send + 'static>(&self, servo: &Arc<Mutex<Servo<D>>>, position: f32,
+        duration: Duration, easing: Easing) -> MotionHandle {
+        self.move_to(servo, position, duration, easing)
+    }
+
+    /**
+     * Fade a LED, like PwmLed::fade_to without blocking
+     * @param led           LED to fade
+     * @param luminosity    wanted luminosity
+     * @param duration      time of the transition
+     * @param easing        progress curve
+     * @return the handle of the motion
+     */
+    pub fn fade_to<P: PwmChannel + Send + 'static>(&self, led: &Arc<Mutex<PwmLed<P>>>, luminosity: f32,
+        duration: Duration, easing: Easing) -> MotionHandle {
+        self.move_to(led, luminosity.clamp(0.0, 1.0), duration, easing)
+    }
+
+    /**
+     * Fade a RGB LED, like RGBLed::fade_to without blocking
+     * @param led           LED to fade
+     * @param color         wanted color
+     * @param duration      time of the transition
+     * @param easing        progress curve
+     * @return the handle of the motion
+     */
+    pub fn fade_color_to<P: PwmChannel + Send + 'static>(&self, led: &Arc<Mutex<RGBLed<P>>>,
+        (r, g, b): (f32, f32, f32), duration: Duration, easing: Easing) -> MotionHandle {
+        self.move_to(led, (r.clamp(0.0, 1.0), g.clamp(0.0, 1.0), b.clamp(0.0, 1.0)), duration, easing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Value keeping every change with the time it was made
+     */
+    struct Recorder {
+        clock: ManualClock,
+        value: f32,
+        fail_above: f32,
+        values: Vec<(Duration, f32)>,
+    }
+
+    impl Movable for Recorder {
+        type Value = f32;
+
+        fn current(&mut self) -> Result<f32> {
+            Ok(self.value)
+        }
+
+        fn apply(&mut self, value: f32) -> Result<()> {
+            if value > self.fail_above {
+                return Err(Error::Protocol(format!("{} is too high", value)));
+            }
+            self.value = value;
+            self.values.push((self.clock.now(), value));
+            Ok(())
+        }
+    }
+
+    fn recorder(clock: &ManualClock) -> Arc<Mutex<Recorder>> {
+        Arc::new(Mutex::new(Recorder {
+            clock: clock.clone(),
+            value: 0.0,
+            fail_above: f32::INFINITY,
+            values: Vec::new(),
+        }))
+    }
+
+    fn scheduler(clock: &ManualClock) -> MotionScheduler {
+        MotionScheduler::new_with_clock(Duration::from_millis(10), Arc::new(clock.clone()))
+    }
+
+    #[test]
+    fn wait_returns_at_the_end_of_the_motion() {
+        let clock = ManualClock::new();
+        let scheduler = scheduler(&clock);
+        let target = recorder(&clock);
+        let guard = target.lock().unwrap();
+        let start = clock.now();
+        let handle = scheduler.move_to(&target, 100.0, Duration::from_secs(1), Easing::Linear);
+        assert!(!handle.is_done());
+        drop(guard);
+        handle.wait().unwrap();
+
+        let target = target.lock().unwrap();
+        let (end, value) = *target.values.last().unwrap();
+        assert_eq!(value, 100.0);
+        assert!(end - start >= Duration::from_secs(1), "ended at {:?}", end - start);
+        assert!(target.values.windows(2).all(|pair| pair[0].0 < pair[1].0 && pair[0].1 <= pair[1].1));
+        assert!(target.values.len() > 10);
+    }
+
+    #[test]
+    fn cancelled_motion_stops_moving() {
+        let clock = ManualClock::new();
+        let scheduler = scheduler(&clock);
+        let (cancelled, other) = (recorder(&clock), recorder(&clock));
+        let guard = cancelled.lock().unwrap();
+        let handle = scheduler.move_to(&cancelled, 100.0, Duration::from_secs(1), Easing::Linear);
+        handle.cancel();
+        assert!(handle.is_done());
+        drop(guard);
+        // Let the scheduler run longer than the cancelled motion
+        scheduler.move_to(&other, 100.0, Duration::from_secs(2), Easing::Linear).wait().unwrap();
+        handle.wait().unwrap();
+
+        // The update started before the cancellation is the only one
+        let values = &cancelled.lock().unwrap().values;
+        assert!(values.len() <= 1 && values.iter().all(|&(_, value)| value == 0.0), "{:?}", values);
+    }
+
+    #[test]
+    fn new_motion_replaces_the_running_one() {
+        let clock = ManualClock::new();
+        let scheduler = scheduler(&clock);
+        let target = recorder(&clock);
+        let guard = target.lock().unwrap();
+        let first = scheduler.move_to(&target, 100.0, Duration::from_secs(1), Easing::Linear);
+        let second = scheduler.move_to(&target, -100.0, Duration::from_secs(1), Easing::Linear);
+        drop(guard);
+        second.wait().unwrap();
+        assert!(first.is_done());
+        first.wait().unwrap();
+
+        let target = target.lock().unwrap();
+        assert_eq!(target.value, -100.0);
+        assert!(target.values.iter().all(|&(_, value)| value <= 0.0), "{:?}", target.values);
+    }
+
+    #[test]
+    fn apply_errors_come_back_through_wait() {
+        let clock = ManualClock::new();
+        let scheduler = scheduler(&clock);
+        let target = recorder(&clock);
+        target.lock().unwrap().fail_above = 50.0;
+        let handle = scheduler.move_to(&target, 100.0, Duration::from_secs(1), Easing::Linear);
+        assert!(matches!(handle.wait(), Err(Error::Protocol(_))));
+        assert!(target.lock().unwrap().value <= 50.0);
+    }
+
+    const EASINGS: [Easing; 8] = [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut, Easing::Cubic,
+        Easing::Sine, Easing::Trapezoidal { accel: 0.2 }, Easing::Trapezoidal { accel: 0.0 }];
+
+    #[test]
+    fn easings_go_from_0_to_1() {
+        for easing in EASINGS.iter() {
+            assert_eq!(easing.progress(0.0), 0.0, "{:?}", easing);
+            assert!((easing.progress(1.0) - 1.0).abs() < 1e-6, "{:?}", easing);
+            assert_eq!((easing.progress(-1.0), easing.progress(2.0)), (easing.progress(0.0), easing.progress(1.0)));
+            let mut previous = 0.0;
+            for step in 1..=1000 {
+                let progress = easing.progress(step as f32 / 1000.0);
+                assert!(progress >= previous, "{:?} goes back at {}", easing, step);
+                previous = progress;
+            }
+        }
+        assert_eq!(Easing::EaseIn.progress(0.5), 0.25);
+        assert_eq!(Easing::EaseOut.progress(0.5), 0.75);
+        for easing in [Easing::EaseInOut, Easing::Cubic, Easing::Sine, Easing::Trapezoidal { accel: 0.3 }].iter() {
+            assert!((easing.progress(0.5) - 0.5).abs() < 1e-6, "{:?}", easing);
+        }
+    }
+
+    #[test]
+    fn trapezoidal_is_continuous_and_clamped() {
+        let easing = Easing::Trapezoidal { accel: 0.25 };
+        for &t in [0.25f32, 0.75].iter() {
+            let (before, after) = (easing.progress(t - 1e-4), easing.progress(t + 1e-4));
+            assert!((after - before).abs() < 1e-3, "jump at {}: {} then {}", t, before, after);
+        }
+        // Constant speed between the ramps
+        assert!((easing.progress(0.5) - easing.progress(0.4) - 0.1 / 0.75).abs() < 1e-5);
+
+        let clamped = Easing::Trapezoidal { accel: 0.9 };
+        let half = Easing::Trapezoidal { accel: 0.5 };
+        let linear = Easing::Trapezoidal { accel: -1.0 };
+        for step in 0..=10 {
+            let t = step as f32 / 10.0;
+            assert_eq!(clamped.progress(t), half.progress(t));
+            assert_eq!(linear.progress(t), t);
+        }
+    }
+}
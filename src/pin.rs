@@ -1,4 +1,11 @@
-// TODO get from config pin
+use crate::clock::*;
+use crate::sysfs::Sysfs;
+use std::sync::Arc;
+
+/**
+ * Shortcut for the pins used on the robot. Any other header pin can be
+ * retrieved with Pin::from_name
+ */
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Gpio {
     P8_13=23,
@@ -10,52 +17,345 @@ pub enum Gpio {
     P9_28=113,
 }
 
+impl Gpio {
+    /**
+     * @return the header pin for this gpio
+     */
+    pub fn pin(self) -> &'static Pin {
+        Pin::from_gpio(self as u32).expect("Gpio not on the header")
+    }
+}
+
+/**
+ * PWM module reachable from a pin
+ * sysfs: pwmchip number, index: channel in the chip,
+ * chip: EPWMSS address, addr: PWM module address
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PwmInfo {
+    pub name: &'static str,
+    pub sysfs: u32,
+    pub index: u32,
+    pub chip: &'static str,
+    pub addr: &'static str,
+}
+
+/**
+ * Alternate function of a pin
+ * bus: peripheral number, signal: e.g. TXD, SCL, CS0
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Function {
+    Uart { bus: u8, signal: &'static str },
+    I2c { bus: u8, signal: &'static str },
+    Spi { bus: u8, signal: &'static str },
+}
+
+/**
+ * What a pin can be used for
+ */
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Capabilities {
+    pub gpio: bool,
+    pub pwm: bool,
+    pub adc: bool,
+    pub uart: bool,
+    pub i2c: bool,
+    pub spi: bool,
+}
+
+/**
+ * A pin of the P8/P9 headers
+ * key: name of the pinmux helper (ocp:<key>_pinmux) if the pin can be muxed
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pin {
+    pub name: &'static str,
+    pub gpio: Option<u32>,
+    pub key: Option<&'static str>,
+    pub pwm: Option<PwmInfo>,
+    pub adc: Option<u8>,
+    pub functions: &'static [Function],
+}
+
+const EHRPWM0A: PwmInfo = PwmInfo { name: "ehrpwm0A", sysfs: 1, index: 0, chip: "48300000", addr: "48300200" };
+const EHRPWM0B: PwmInfo = PwmInfo { name: "ehrpwm0B", sysfs: 1, index: 1, chip: "48300000", addr: "48300200" };
+const EHRPWM1A: PwmInfo = PwmInfo { name: "ehrpwm1A", sysfs: 4, index: 0, chip: "48302000", addr: "48302200" };
+const EHRPWM1B: PwmInfo = PwmInfo { name: "ehrpwm1B", sysfs: 4, index: 1, chip: "48302000", addr: "48302200" };
+const EHRPWM2A: PwmInfo = PwmInfo { name: "ehrpwm2A", sysfs: 7, index: 0, chip: "48304000", addr: "48304200" };
+const EHRPWM2B: PwmInfo = PwmInfo { name: "ehrpwm2B", sysfs: 7, index: 1, chip: "48304000", addr: "48304200" };
+const ECAP0: PwmInfo = PwmInfo { name: "ecap0", sysfs: 0, index: 0, chip: "48300000", addr: "48300100" };
+const ECAP2: PwmInfo = PwmInfo { name: "ecap2", sysfs: 6, index: 0, chip: "48304000", addr: "48304100" };
+
+const fn uart(bus: u8, signal: &'static str) -> Function {
+    Function::Uart { bus, signal }
+}
+
+const fn i2c(bus: u8, signal: &'static str) -> Function {
+    Function::I2c { bus, signal }
+}
+
+const fn spi(bus: u8, signal: &'static str) -> Function {
+    Function::Spi { bus, signal }
+}
+
+impl Pin {
+    const fn power(name: &'static str) -> Pin {
+        Pin { name, gpio: None, key: None, pwm: None, adc: None, functions: &[] }
+    }
+
+    const fn gpio(name: &'static str, gpio: u32) -> Pin {
+        Pin { name, gpio: Some(gpio), key: Some(name), pwm: None, adc: None, functions: &[] }
+    }
+
+    const fn adc(name: &'static str, channel: u8) -> Pin {
+        Pin { name, gpio: None, key: None, pwm: None, adc: Some(channel), functions: &[] }
+    }
+
+    const fn with_pwm(self, pwm: PwmInfo) -> Pin {
+        Pin { pwm: Some(pwm), ..self }
+    }
+
+    const fn with_functions(self, functions: &'static [Function]) -> Pin {
+        Pin { functions, ..self }
+    }
+
+    /**
+     * @return every pin of the P8 and P9 headers
+     */
+    pub fn all() -> &'static [Pin] {
+        PINS
+    }
+
+    /**
+     * Find a pin by its header name
+     * @param name      e.g. "P9_14"
+     * @return the pin if it exists
+     */
+    pub fn from_name(name: &str) -> Option<&'static Pin> {
+        PINS.iter().find(|pin| pin.name == name)
+    }
+
+    /**
+     * Find a pin by its gpio number
+     * @param gpio      e.g. 50 for GPIO1_18
+     * @return the pin if it exists
+     */
+    pub fn from_gpio(gpio: u32) -> Option<&'static Pin> {
+        PINS.iter().find(|pin| pin.gpio == Some(gpio))
+    }
+
+    /**
+     * @return what the pin can be used for
+     */
+    pub fn capabilities(&self) -> Capabilities {
+        let has = |f: fn(&Function) -> bool| self.functions.iter().any(f);
+        Capabilities {
+            gpio: self.gpio.is_some(),
+            pwm: self.pwm.is_some(),
+            adc: self.adc.is_some(),
+            uart: has(|f| matches!(f, Function::Uart { .. })),
+            i2c: has(|f| matches!(f, Function::I2c { .. })),
+            spi: has(|f| matches!(f, Function::Spi { .. })),
+        }
+    }
+}
+
+// Copied from https://github.com/jadonk/bonescript/blob/master/src/bone.js
+static PINS: &[Pin] = &[
+    Pin::power("P8_1"),
+    Pin::power("P8_2"),
+    Pin::gpio("P8_3", 38),
+    Pin::gpio("P8_4", 39),
+    Pin::gpio("P8_5", 34),
+    Pin::gpio("P8_6", 35),
+    Pin::gpio("P8_7", 66),
+    Pin::gpio("P8_8", 67),
+    Pin::gpio("P8_9", 69),
+    Pin::gpio("P8_10", 68),
+    Pin::gpio("P8_11", 45),
+    Pin::gpio("P8_12", 44),
+    Pin::gpio("P8_13", 23).with_pwm(EHRPWM2B),
+    Pin::gpio("P8_14", 26),
+    Pin::gpio("P8_15", 47),
+    Pin::gpio("P8_16", 46),
+    Pin::gpio("P8_17", 27),
+    Pin::gpio("P8_18", 65),
+    Pin::gpio("P8_19", 22).with_pwm(EHRPWM2A),
+    Pin::gpio("P8_20", 63),
+    Pin::gpio("P8_21", 62),
+    Pin::gpio("P8_22", 37),
+    Pin::gpio("P8_23", 36),
+    Pin::gpio("P8_24", 33),
+    Pin::gpio("P8_25", 32),
+    Pin::gpio("P8_26", 61),
+    Pin::gpio("P8_27", 86),
+    Pin::gpio("P8_28", 88),
+    Pin::gpio("P8_29", 87),
+    Pin::gpio("P8_30", 89),
+    Pin::gpio("P8_31", 10).with_functions(&[uart(5, "CTSN")]),
+    Pin::gpio("P8_32", 11).with_functions(&[uart(5, "RTSN")]),
+    Pin::gpio("P8_33", 9).with_functions(&[uart(4, "RTSN")]),
+    Pin::gpio("P8_34", 81).with_pwm(EHRPWM1B).with_functions(&[uart(3, "RTSN")]),
+    Pin::gpio("P8_35", 8).with_functions(&[uart(4, "CTSN")]),
+    Pin::gpio("P8_36", 80).with_pwm(EHRPWM1A).with_functions(&[uart(3, "CTSN")]),
+    Pin::gpio("P8_37", 78).with_functions(&[uart(5, "TXD")]),
+    Pin::gpio("P8_38", 79).with_functions(&[uart(5, "RXD")]),
+    Pin::gpio("P8_39", 76),
+    Pin::gpio("P8_40", 77),
+    Pin::gpio("P8_41", 74),
+    Pin::gpio("P8_42", 75),
+    Pin::gpio("P8_43", 72),
+    Pin::gpio("P8_44", 73),
+    Pin::gpio("P8_45", 70).with_pwm(EHRPWM2A),
+    Pin::gpio("P8_46", 71).with_pwm(EHRPWM2B),
+    Pin::power("P9_1"),
+    Pin::power("P9_2"),
+    Pin::power("P9_3"),
+    Pin::power("P9_4"),
+    Pin::power("P9_5"),
+    Pin::power("P9_6"),
+    Pin::power("P9_7"),
+    Pin::power("P9_8"),
+    Pin::power("P9_9"),
+    Pin::power("P9_10"),
+    Pin::gpio("P9_11", 30).with_functions(&[uart(4, "RXD")]),
+    Pin::gpio("P9_12", 60),
+    Pin::gpio("P9_13", 31).with_functions(&[uart(4, "TXD")]),
+    Pin::gpio("P9_14", 50).with_pwm(EHRPWM1A),
+    Pin::gpio("P9_15", 48),
+    Pin::gpio("P9_16", 51).with_pwm(EHRPWM1B),
+    Pin::gpio("P9_17", 5).with_functions(&[i2c(1, "SCL"), spi(0, "CS0")]),
+    Pin::gpio("P9_18", 4).with_functions(&[i2c(1, "SDA"), spi(0, "D1")]),
+    Pin::gpio("P9_19", 13).with_functions(&[i2c(2, "SCL"), uart(1, "RTSN"), spi(1, "CS1")]),
+    Pin::gpio("P9_20", 12).with_functions(&[i2c(2, "SDA"), uart(1, "CTSN"), spi(1, "CS0")]),
+    Pin::gpio("P9_21", 3).with_pwm(EHRPWM0B).with_functions(&[uart(2, "TXD"), i2c(2, "SCL"), spi(0, "D0")]),
+    Pin::gpio("P9_22", 2).with_pwm(EHRPWM0A).with_functions(&[uart(2, "RXD"), i2c(2, "SDA"), spi(0, "SCLK")]),
+    Pin::gpio("P9_23", 49),
+    Pin::gpio("P9_24", 15).with_functions(&[uart(1, "TXD"), i2c(1, "SCL")]),
+    Pin::gpio("P9_25", 117),
+    Pin::gpio("P9_26", 14).with_functions(&[uart(1, "RXD"), i2c(1, "SDA")]),
+    Pin::gpio("P9_27", 115),
+    Pin::gpio("P9_28", 113).with_pwm(ECAP2).with_functions(&[spi(1, "CS0")]),
+    Pin::gpio("P9_29", 111).with_pwm(EHRPWM0B).with_functions(&[spi(1, "D0")]),
+    Pin::gpio("P9_30", 112).with_functions(&[spi(1, "D1")]),
+    Pin::gpio("P9_31", 110).with_pwm(EHRPWM0A).with_functions(&[spi(1, "SCLK")]),
+    Pin::power("P9_32"),
+    Pin::adc("P9_33", 4),
+    Pin::power("P9_34"),
+    Pin::adc("P9_35", 6),
+    Pin::adc("P9_36", 5),
+    Pin::adc("P9_37", 2),
+    Pin::adc("P9_38", 3),
+    Pin::adc("P9_39", 0),
+    Pin::adc("P9_40", 1),
+    Pin::gpio("P9_41", 20),
+    Pin::gpio("P9_42", 7).with_pwm(ECAP0).with_functions(&[spi(1, "CS1"), uart(3, "TXD")]),
+    Pin::power("P9_43"),
+    Pin::power("P9_44"),
+    Pin::power("P9_45"),
+    Pin::power("P9_46"),
+];
+
 pub struct Pwm {
     pub sysfs: u32,
     pub index: u32,
     pub chip: String,
     pub addr: String,
     pub key: String,
+    pub fs: Sysfs,
+    pub clock: Arc<dyn Clock>,
+    /// set once this instance registered its period in the PwmChipRegistry
+    pub(crate) claimed: bool,
+}
+
+pub struct GpioPin {
+    pub gpio: u32,
+    pub fs: Sysfs,
+}
+
+/**
+ * Get the PWM module of a pin
+ * @param pin       Header pin
+ * @return the Pwm if the pin supports it
+ */
+pub fn pin_to_pwm(pin: &Pin) -> Option<Pwm> {
+    pin_to_pwm_with_sysfs(pin, Sysfs::default())
+}
+
+/**
+ * Get the PWM module of a pin in a given sysfs tree
+ * @param pin       Header pin
+ * @param fs        sysfs tree to use
+ * @return the Pwm if the pin supports it
+ */
+pub fn pin_to_pwm_with_sysfs(pin: &Pin, fs: Sysfs) -> Option<Pwm> {
+    pin_to_pwm_with_clock(pin, fs, system_clock())
+}
+
+/**
+ * Get the PWM module of a pin in a given sysfs tree
+ * @param pin       Header pin
+ * @param fs        sysfs tree to use
+ * @param clock     clock used to wait for the export of the channel
+ * @return the Pwm if the pin supports it
+ */
+pub fn pin_to_pwm_with_clock(pin: &Pin, fs: Sysfs, clock: Arc<dyn Clock>) -> Option<Pwm> {
+    let pwm = pin.pwm?;
+    Some(Pwm {
+        sysfs: pwm.sysfs,
+        index: pwm.index,
+        chip: String::from(pwm.chip),
+        addr: String::from(pwm.addr),
+        key: String::from(pin.key?),
+        fs,
+        clock,
+        claimed: false,
+    })
 }
 
-// Copied from https://github.com/jadonk/bonescript/blob/master/src/bone.js
 pub fn gpio_to_pwm(gpio: &Gpio) -> Option<Pwm> {
-    match gpio {
-        Gpio::P9_14 => Some(Pwm {
-            sysfs: 4,
-            index: 0,
-            chip: String::from("48302000"),
-            addr: String::from("48302200"),
-            key: String::from("P9_14")
-        }),
-        Gpio::P9_16 => Some(Pwm {
-            sysfs: 4,
-            index: 1,
-            chip: String::from("48302000"),
-            addr: String::from("48302200"),
-            key: String::from("P9_16")
-        }),
-        Gpio::P9_22 => Some(Pwm {
-            sysfs: 1,
-            index: 0,
-            chip: String::from("48300000"),
-            addr: String::from("48300200"),
-            key: String::from("P9_22")
-        }),
-        Gpio::P9_28 => Some(Pwm {
-            sysfs: 6,
-            index: 0,
-            chip: String::from("48304000"),
-            addr: String::from("48304100"),
-            key: String::from("P9_28")
-        }),
-        Gpio::P8_13 => Some(Pwm {
-            sysfs: 7,
-            index: 1,
-            chip: String::from("48304000"),
-            addr: String::from("48304200"),
-            key: String::from("P8_13")
-        }),
-        _ => None,
-    }
-}
\ No newline at end of file
+    pin_to_pwm(gpio.pin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_are_found_by_name_and_gpio() {
+        let pin = Pin::from_name("P9_14").unwrap();
+        assert_eq!(pin.gpio, Some(50));
+        assert_eq!(pin.key, Some("P9_14"));
+        assert_eq!(pin.pwm.map(|pwm| (pwm.name, pwm.sysfs, pwm.index)), Some(("ehrpwm1A", 4, 0)));
+        assert_eq!(Pin::from_gpio(50), Some(pin));
+        assert_eq!(Pin::from_name("P9_99"), None);
+        assert_eq!(Pin::from_gpio(1000), None);
+
+        let adc = Pin::from_name("P9_33").unwrap();
+        assert_eq!((adc.gpio, adc.adc), (None, Some(4)));
+        assert_eq!(adc.capabilities(), Capabilities { adc: true, ..Capabilities::default() });
+
+        let serial = Pin::from_name("P9_24").unwrap();
+        assert_eq!(serial.functions, &[uart(1, "TXD"), i2c(1, "SCL")]);
+        assert_eq!(serial.capabilities(), Capabilities { gpio: true, uart: true, i2c: true, ..Capabilities::default() });
+
+        assert_eq!(Pin::from_name("P9_1").unwrap().capabilities(), Capabilities::default());
+    }
+
+    #[test]
+    fn shortcuts_match_the_header() {
+        let pwms: Vec<Option<&str>> = [Gpio::P8_13, Gpio::P9_12, Gpio::P9_14, Gpio::P9_16, Gpio::P9_18, Gpio::P9_22,
+            Gpio::P9_28].iter().map(|gpio| {
+                assert_eq!(gpio.pin().gpio, Some(*gpio as u32));
+                gpio.pin().pwm.map(|pwm| pwm.name)
+            }).collect();
+        assert_eq!(pwms, [Some("ehrpwm2B"), None, Some("ehrpwm1A"), Some("ehrpwm1B"), None, Some("ehrpwm0A"),
+            Some("ecap2")]);
+
+        let pwm = gpio_to_pwm(&Gpio::P9_16).unwrap();
+        assert_eq!((pwm.sysfs, pwm.index, pwm.chip.as_str(), pwm.addr.as_str(), pwm.key.as_str()),
+            (4, 1, "48302000", "48302200", "P9_16"));
+        assert!(gpio_to_pwm(&Gpio::P9_12).is_none());
+    }
+}
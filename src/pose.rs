@@ -0,0 +1,230 @@
+use crate::clock::*;
+use crate::error::*;
+use crate::kinematics::*;
+use crate::motion::*;
+use std::time::Duration;
+
+/**
+ * Position of the body relative to its neutral position
+ * x/y/z: translation in mm, x forward, y to the left and z up
+ * roll/pitch/yaw: rotation in degrees around the x, y and z axes,
+ * counterclockwise when looking at the axis from its positive side
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BodyPose {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl BodyPose {
+    /**
+     * Get a pose only moving the body up or down
+     * @param z         height in mm, negative to crouch
+     * @return the new pose
+     */
+    pub fn height(z: f32) -> BodyPose {
+        BodyPose {
+            z,
+            ..BodyPose::default()
+        }
+    }
+
+    /**
+     * Express a planted foot in the coordinates of the moved body
+     * @param foot      foot position in the coordinates of the neutral body
+     * @return the foot position seen from the body in this pose
+     */
+    pub fn foot(&self, foot: &Point3) -> Point3 {
+        let (x, y, z) = (foot.x - self.x, foot.y - self.y, foot.z - self.z);
+        // Undo the rotation: yaw, then pitch, then roll
+        let (sin, cos) = self.yaw.to_radians().sin_cos();
+        let (x, y) = (cos * x + sin * y, -sin * x + cos * y);
+        let (sin, cos) = self.pitch.to_radians().sin_cos();
+        let (x, z) = (cos * x - sin * z, sin * x + cos * z);
+        let (sin, cos) = self.roll.to_radians().sin_cos();
+        let (y, z) = (cos * y + sin * z, -sin * y + cos * z);
+        Point3::new(x, y, z)
+    }
+
+    /**
+     * @param feet      planted feet in the coordinates of the neutral body
+     * @return the feet seen from the body in this pose
+     */
+    pub fn feet(&self, feet: &[Point3]) -> Vec<Point3> {
+        feet.iter().map(|foot| self.foot(foot)).collect()
+    }
+}
+
+impl Interpolate for BodyPose {
+    fn interpolate(self, to: BodyPose, ratio: f32) -> BodyPose {
+        BodyPose {
+            x: self.x.interpolate(to.x, ratio),
+            y: self.y.interpolate(to.y, ratio),
+            z: self.z.interpolate(to.z, ratio),
+            roll: self.roll.interpolate(to.roll, ratio),
+            pitch: self.pitch.interpolate(to.pitch, ratio),
+            yaw: self.yaw.interpolate(to.yaw, ratio),
+        }
+    }
+}
+
+/**
+ * A robot moving its body while its feet stay planted
+ */
+pub struct Body {
+    hexapod: Hexapod,
+    feet: Vec<Point3>,
+    pose: BodyPose,
+}
+
+impl Body {
+    /**
+     * Create a new body with the feet where all the joints are at 0, which
+     * is the position reached with ServoGroup::set_position(90.0) when the
+     * joints are centered on 90
+     * @param hexapod   robot to move
+     * @return the new body
+     */
+    pub fn new(hexapod: Hexapod) -> Body {
+        let feet = hexapod.neutral_feet();
+        Body::new_with_feet(hexapod, feet)
+    }
+
+    /**
+     * Create a new body with the feet planted at given positions
+     * @param hexapod   robot to move
+     * @param feet      feet in the coordinates of the neutral body, in the order of the legs
+     * @return the new body
+     */
+    pub fn new_with_feet(hexapod: Hexapod, feet: Vec<Point3>) -> Body {
+        Body {
+            hexapod,
+            feet,
+            pose: BodyPose::default(),
+        }
+    }
+
+    /**
+     * @return the robot
+     */
+    pub fn hexapod(&mut self) -> &mut Hexapod {
+        &mut self.hexapod
+    }
+
+    /**
+     * @return the robot
+     */
+    pub fn into_hexapod(self) -> Hexapod {
+        self.hexapod
+    }
+
+    /**
+     * @return the planted feet in the coordinates of the neutral body
+     */
+    pub fn planted_feet(&self) -> &[Point3] {
+        &self.feet
+    }
+
+    /**
+     * @return the last pose applied
+     */
+    pub fn pose(&self) -> BodyPose {
+        self.pose
+    }
+
+    /**
+     * Move the body, the feet don't move
+     * @param pose      wanted pose
+     * @return if the operation was successful, Error::Unreachable if a
+     * leg can't follow, the body doesn't move then
+     */
+    pub fn set_pose(&mut self, pose: BodyPose) -> Result<()> {
+        self.hexapod.set_feet(&pose.feet(&self.feet))?;
+        self.pose = pose;
+        Ok(())
+    }
+
+    /**
+     * Move the body smoothly, blocking
+     * @param pose              wanted pose
+     * @param duration          time of the move
+     * @param update_period     time between two updates
+     * @param easing            progress curve
+     * @param clock             clock used to wait
+     * @return if the operation was successful
+     */
+    pub fn move_to(&mut self, pose: BodyPose, duration: Duration, update_period: Duration, easing: Easing,
+        clock: &dyn Clock) -> Result<()> {
+        let from = self.pose;
+        run_transition(clock, duration, update_period, easing,
+            |progress| self.set_pose(from.interpolate(pose, progress)))
+    }
+}
+
+impl Movable for Body {
+    type Value = BodyPose;
+
+    fn current(&mut self) -> Result<BodyPose> {
+        Ok(self.pose)
+    }
+
+    fn apply(&mut self, pose: BodyPose) -> Result<()> {
+        self.set_pose(pose)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feet() -> Vec<Point3> {
+        vec![Point3::new(120.0, 100.0, -90.0), Point3::new(0.0, 150.0, -90.0), Point3::new(-120.0, -100.0, -80.0)]
+    }
+
+    fn assert_close(a: &[Point3], b: &[Point3], tolerance: f32) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!(a.distance(b) < tolerance, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn poses_move_the_feet_the_other_way() {
+        assert_eq!(BodyPose::default().feet(&feet()), feet());
+
+        let raised: Vec<Point3> = feet().iter().map(|foot| Point3::new(foot.x, foot.y, foot.z - 20.0)).collect();
+        assert_close(&BodyPose::height(20.0).feet(&feet()), &raised, 1e-3);
+
+        let shifted = BodyPose { x: 10.0, y: -5.0, ..BodyPose::default() };
+        assert_eq!(shifted.foot(&Point3::new(120.0, 100.0, -90.0)), Point3::new(110.0, 105.0, -90.0));
+
+        // Turning the body to the left moves the feet to its right
+        let turned = BodyPose { yaw: 90.0, ..BodyPose::default() };
+        assert_close(&turned.feet(&[Point3::new(100.0, 0.0, -90.0), Point3::new(0.0, 100.0, -90.0)]),
+            &[Point3::new(0.0, -100.0, -90.0), Point3::new(100.0, 0.0, -90.0)], 1e-3);
+
+        // Leaning forward brings the front feet up toward the body
+        let pitched = BodyPose { pitch: 10.0, ..BodyPose::default() };
+        assert!(pitched.foot(&Point3::new(100.0, 0.0, -90.0)).z > -90.0);
+    }
+
+    #[test]
+    fn body_keeps_its_pose_when_a_leg_cant_follow() {
+        let mut body = Body::new(crate::kinematics::tests::hexapod());
+        let pose = BodyPose { z: 10.0, yaw: 5.0, ..BodyPose::default() };
+        body.set_pose(pose).unwrap();
+        assert_eq!(body.pose(), pose);
+        // Servo targets are rounded to 0.25 µs
+        let feet = body.hexapod().feet().unwrap();
+        assert_close(&feet, &pose.feet(body.planted_feet()), 0.1);
+
+        let too_high = BodyPose::height(200.0);
+        assert!(matches!(body.set_pose(too_high), Err(Error::Unreachable(_))));
+        assert_eq!(body.pose(), pose);
+        assert_close(&body.hexapod().feet().unwrap(), &feet, 1e-3);
+    }
+}
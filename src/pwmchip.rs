@@ -0,0 +1,193 @@
+use crate::error::*;
+use crate::pin::Pwm;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/**
+ * Channels of a pwmchip currently in use
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PwmChipUsage {
+    pub period_ns: u32,
+    /// (index, pin key) of every channel using the chip, the first one owns the period
+    pub channels: Vec<(u32, String)>,
+}
+
+/**
+ * Both channels of an EPWMSS module share the same period. This registry
+ * remembers which Pwm uses which pwmchip, so a second channel can't
+ * silently change the period of the first one.
+ */
+#[derive(Debug, Default)]
+pub struct PwmChipRegistry {
+    chips: Mutex<BTreeMap<(PathBuf, u32), PwmChipUsage>>,
+}
+
+impl PwmChipRegistry {
+    /**
+     * @return the registry used by every Pwm
+     */
+    pub fn global() -> &'static PwmChipRegistry {
+        static REGISTRY: OnceLock<PwmChipRegistry> = OnceLock::new();
+        REGISTRY.get_or_init(PwmChipRegistry::default)
+    }
+
+    fn chip_of(pwm: &Pwm) -> (PathBuf, u32) {
+        (pwm.fs.root.clone(), pwm.sysfs)
+    }
+
+    fn conflict(usage: &PwmChipUsage, pwm: &Pwm, period_ns: u32) -> Result<()> {
+        let others = usage.channels.iter().find(|(index, _)| *index != pwm.index);
+        if let Some((_, owner)) = others {
+            if usage.period_ns != period_ns {
+                warn!("{} can't use period {} ns, {} already uses {} ns on pwmchip{}",
+                    pwm.key, period_ns, owner, usage.period_ns, pwm.sysfs);
+                return Err(Error::PwmPeriodConflict {
+                    pin: pwm.key.clone(),
+                    owner: owner.clone(),
+                    period_ns: usage.period_ns,
+                    requested_ns: period_ns,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Check if pwm could use a given period, without registering it
+     * @param pwm           Pwm wanting the period
+     * @param period_ns     Wanted period
+     * @return if the period can be used
+     */
+    pub fn check(&self, pwm: &Pwm, period_ns: u32) -> Result<()> {
+        match self.chips.lock().unwrap().get(&PwmChipRegistry::chip_of(pwm)) {
+            Some(usage) => PwmChipRegistry::conflict(usage, pwm, period_ns),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Register pwm with a given period. Fails if another channel of the
+     * same pwmchip uses a different period.
+     * @param pwm           Pwm wanting the period, marked as claimed on success
+     * @param period_ns     Wanted period
+     * @return if the period can be used
+     */
+    pub fn claim(&self, pwm: &mut Pwm, period_ns: u32) -> Result<()> {
+        let mut chips = self.chips.lock().unwrap();
+        let usage = chips.entry(PwmChipRegistry::chip_of(pwm)).or_insert(PwmChipUsage {
+            period_ns,
+            channels: Vec::new(),
+        });
+        PwmChipRegistry::conflict(usage, pwm, period_ns)?;
+        usage.period_ns = period_ns;
+        if !usage.channels.iter().any(|(index, _)| *index == pwm.index) {
+            usage.channels.push((pwm.index, pwm.key.clone()));
+        }
+        pwm.claimed = true;
+        Ok(())
+    }
+
+    /**
+     * Unregister pwm, if it claimed its channel. Another instance of the
+     * same pin which never claimed it leaves the claim in place.
+     * @param pwm           Pwm to release
+     */
+    pub fn release(&self, pwm: &Pwm) {
+        if !pwm.claimed {
+            return;
+        }
+        let mut chips = self.chips.lock().unwrap();
+        let chip = PwmChipRegistry::chip_of(pwm);
+        if let Some(usage) = chips.get_mut(&chip) {
+            usage.channels.retain(|(index, _)| *index != pwm.index);
+            if usage.channels.is_empty() {
+                chips.remove(&chip);
+            }
+        }
+    }
+
+    /**
+     * @param pwm           Pwm to check
+     * @return how the pwmchip of pwm is used, if it is used
+     */
+    pub fn usage(&self, pwm: &Pwm) -> Option<PwmChipUsage> {
+        self.chips.lock().unwrap().get(&PwmChipRegistry::chip_of(pwm)).cloned()
+    }
+
+    /**
+     * @param pwm           Pwm to check
+     * @return the period imposed by another channel of the same pwmchip
+     */
+    pub fn shared_period_ns(&self, pwm: &Pwm) -> Option<u32> {
+        let usage = self.usage(pwm)?;
+        match usage.channels.iter().any(|(index, _)| *index != pwm.index) {
+            true => Some(usage.period_ns),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pin::*;
+    use crate::sysfs::Sysfs;
+
+    fn pwm(gpio: Gpio, tree: &str) -> Pwm {
+        // Every tree has its own chips in the registry
+        let root = std::env::temp_dir().join(format!("cucaracha-pwmchip-{}", tree));
+        pin_to_pwm_with_sysfs(gpio.pin(), Sysfs::new(root)).unwrap()
+    }
+
+    #[test]
+    fn channels_share_the_period() {
+        let registry = PwmChipRegistry::global();
+        let (mut a, mut b) = (pwm(Gpio::P9_14, "share"), pwm(Gpio::P9_16, "share"));
+        registry.claim(&mut a, 20000000).unwrap();
+        assert_eq!(registry.shared_period_ns(&a), None);
+        assert_eq!(registry.shared_period_ns(&b), Some(20000000));
+
+        match registry.claim(&mut b, 10000000) {
+            Err(Error::PwmPeriodConflict { pin, owner, period_ns, requested_ns }) =>
+                assert_eq!((pin.as_str(), owner.as_str(), period_ns, requested_ns), ("P9_16", "P9_14", 20000000, 10000000)),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(registry.check(&b, 10000000).is_err());
+        registry.check(&b, 20000000).unwrap();
+        registry.claim(&mut b, 20000000).unwrap();
+        assert_eq!(registry.usage(&a).unwrap().channels, [(0, String::from("P9_14")), (1, String::from("P9_16"))]);
+        assert!(registry.claim(&mut a, 10000000).is_err());
+
+        // Another pwmchip doesn't care
+        let mut other = pwm(Gpio::P9_22, "share");
+        registry.claim(&mut other, 10000000).unwrap();
+    }
+
+    #[test]
+    fn dropped_channels_are_released() {
+        let registry = PwmChipRegistry::global();
+        let (mut a, mut b) = (pwm(Gpio::P9_14, "drop"), pwm(Gpio::P9_16, "drop"));
+        registry.claim(&mut a, 20000000).unwrap();
+        registry.claim(&mut b, 20000000).unwrap();
+        drop(b);
+        assert_eq!(registry.shared_period_ns(&a), None);
+        // Alone on the chip, the period can change
+        registry.claim(&mut a, 10000000).unwrap();
+        let probe = pwm(Gpio::P9_16, "drop");
+        assert_eq!(registry.shared_period_ns(&probe), Some(10000000));
+        drop(a);
+        assert_eq!(registry.usage(&probe), None);
+    }
+
+    #[test]
+    fn unclaimed_duplicates_keep_the_claim() {
+        let registry = PwmChipRegistry::global();
+        let mut owner = pwm(Gpio::P9_14, "duplicate");
+        registry.claim(&mut owner, 20000000).unwrap();
+        // Same pin, never claimed
+        drop(pwm(Gpio::P9_14, "duplicate"));
+        assert_eq!(registry.usage(&owner).unwrap().channels, [(0, String::from("P9_14"))]);
+    }
+}
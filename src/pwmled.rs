@@ -1,93 +1,89 @@
+use crate::clock::*;
+use crate::error::*;
+use crate::hal::PwmChannel;
+use crate::motion::*;
 use crate::pin::*;
+use crate::sysfs::Sysfs;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct PwmLed {
-    pub pwm: Pwm
+pub struct PwmLed<P: PwmChannel = Pwm> {
+    pub pwm: P,
+    pub clock: Arc<dyn Clock>,
 }
 
-impl PwmLed {
-    pub fn new(gpio: Gpio) -> PwmLed {
-        PwmLed::new_with_luminosity(gpio, 1.0)
+impl PwmLed<Pwm> {
+    pub fn new(pin: &Pin) -> Result<PwmLed> {
+        PwmLed::new_with_luminosity(pin, 1.0)
     }
 
-    pub fn new_with_luminosity(gpio: Gpio, mut luminosity: f32) -> PwmLed {
-        let frequency = 20000;
-        if luminosity > 1.0 {
-            luminosity = 1.0;
-        } else if luminosity < 0.0 {
-            luminosity = 0.0;
-        }
-        let pwm = gpio_to_pwm(&gpio);
-        if pwm.is_none() {
-            panic!("Invalid PWM");
-        }
-        let mut pwm = pwm.unwrap();
-        pwm.start_pwm((luminosity * frequency as f32) as u32, frequency);
+    pub fn new_with_luminosity(pin: &Pin, luminosity: f32) -> Result<PwmLed> {
+        PwmLed::new_with_sysfs(pin, luminosity, Sysfs::default())
+    }
+
+    pub fn new_with_sysfs(pin: &Pin, luminosity: f32, fs: Sysfs) -> Result<PwmLed> {
+        PwmLed::new_with_clock(pin, luminosity, fs, system_clock())
+    }
+
+    pub fn new_with_clock(pin: &Pin, luminosity: f32, fs: Sysfs, clock: Arc<dyn Clock>) -> Result<PwmLed> {
+        let pwm = pin_to_pwm_with_clock(pin, fs, clock.clone())
+            .ok_or_else(|| Error::InvalidPwmPin(pin.name.to_string()))?;
+        Ok(PwmLed::new_from_pwm(pwm, luminosity)?.with_clock(clock))
+    }
+}
+
+impl<P: PwmChannel> PwmLed<P> {
+    pub fn new_from_pwm(mut pwm: P, luminosity: f32) -> Result<PwmLed<P>> {
+        // A LED doesn't care about the period, so follow the one used by the pwmchip if any
+        let frequency = pwm.shared_period_ns().unwrap_or(20000);
+        let luminosity = luminosity.clamp(0.0, 1.0);
+        pwm.start_pwm((luminosity * frequency as f32) as u32, frequency)?;
+        Ok(PwmLed {
+            pwm,
+            clock: system_clock(),
+        })
+    }
+
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> PwmLed<P> {
         PwmLed {
-            pwm
+            clock,
+            ..self
         }
     }
 
-    pub fn set_luminosity(&mut self, mut luminosity: f32) -> bool {
-        if luminosity > 1.0 {
-            luminosity = 1.0;
-        } else if luminosity < 0.0 {
-            luminosity = 0.0;
-        }
-        let duty_cycle = self.pwm.get_period_ns() as f32 * luminosity;
+    pub fn set_luminosity(&mut self, luminosity: f32) -> Result<()> {
+        let luminosity = luminosity.clamp(0.0, 1.0);
+        let duty_cycle = self.pwm.get_period_ns()? as f32 * luminosity;
         self.pwm.set_duty_ns(duty_cycle as u32)
     }
 
 
-    pub fn fade_to(&mut self, mut luminosity: f32, duration_ms: u32, update_period_ms: u32) -> bool {
-        let mut step = duration_ms / update_period_ms;
-        if step == 0 {
-            step = 1;
-        }
-        if luminosity > 1.0 {
-            luminosity = 1.0;
-        } else if luminosity < 0.0 {
-            luminosity = 0.0;
-        }
-
-        let frequency = self.pwm.get_period_ns() as f32;
-        let current_cycle: f32 = self.pwm.get_duty_ns() as f32;
-        let wanted_cycle: f32 = luminosity * frequency;
-        let inc = (wanted_cycle - current_cycle) / step as f32;
-
-        for _ in 0..step {
-            let mut new_cycle = self.pwm.get_duty_ns() as i32 + inc as i32;
-            if new_cycle < 0 {
-                new_cycle = 0;
-            }
-            let new_cycle = new_cycle as u32;
-            if !self.pwm.set_duty_ns(new_cycle) {
-                return false;
-            }
-            std::thread::sleep(std::time::Duration::from_millis(update_period_ms as u64));
-        }
+    pub fn fade_to(&mut self, luminosity: f32, duration_ms: u32, update_period_ms: u32) -> Result<()> {
+        self.fade_to_with_easing(luminosity, duration_ms, update_period_ms, Easing::Linear)
+    }
 
-        true
+    pub fn fade_to_with_easing(&mut self, luminosity: f32, duration_ms: u32, update_period_ms: u32,
+        easing: Easing) -> Result<()> {
+        let luminosity = luminosity.clamp(0.0, 1.0);
+        let current_luminosity = self.get_luminosity()?;
+        let clock = self.clock.clone();
+        run_transition(&*clock, Duration::from_millis(duration_ms as u64), Duration::from_millis(update_period_ms as u64),
+            easing, |progress| self.set_luminosity(current_luminosity.interpolate(luminosity, progress)))
     }
 
 
-    pub fn blink(&mut self, mut proportion: f32, speed: u32) -> bool {
-        if proportion > 1.0 {
-            proportion = 1.0;
-        } else if proportion < 0.0 {
-            proportion = 0.0;
-        }
+    pub fn blink(&mut self, proportion: f32, speed: u32) -> Result<()> {
+        let proportion = proportion.clamp(0.0, 1.0);
 
         let duty_cycle = (proportion * speed as f32) as u32;
-        if !self.pwm.set_period_ns(speed) {
-            return false;
-        }
+        self.pwm.set_period_ns(speed)?;
         self.pwm.set_duty_ns(duty_cycle)
     }
 
-    pub fn get_luminosity(&self) -> f32 {
-        let frequency = self.pwm.get_period_ns() as f32;
-        let duty_cycle = self.pwm.get_duty_ns() as f32;
-        duty_cycle / frequency
+    pub fn get_luminosity(&self) -> Result<f32> {
+        let frequency = self.pwm.get_period_ns()? as f32;
+        let duty_cycle = self.pwm.get_duty_ns()? as f32;
+        Ok(duty_cycle / frequency)
     }
 
-}
\ No newline at end of file
+}
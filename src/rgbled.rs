@@ -1,112 +1,93 @@
+use crate::clock::*;
+use crate::error::*;
+use crate::hal::PwmChannel;
+use crate::motion::*;
 use crate::pin::*;
 use crate::pwmled::*;
+use crate::sysfs::Sysfs;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct RGBLed {
-    pub r_led: PwmLed,
-    pub g_led: PwmLed,
-    pub b_led: PwmLed,
+pub struct RGBLed<P: PwmChannel = Pwm> {
+    pub r_led: PwmLed<P>,
+    pub g_led: PwmLed<P>,
+    pub b_led: PwmLed<P>,
+    pub clock: Arc<dyn Clock>,
 }
 
-impl RGBLed {
-    pub fn new((r_gpio, g_gpio, b_gpio) : (Gpio, Gpio, Gpio)) -> RGBLed {
-        RGBLed::new_with_color((r_gpio, g_gpio, b_gpio), (1.0, 1.0, 1.0))
+impl RGBLed<Pwm> {
+    pub fn new((r_pin, g_pin, b_pin) : (&Pin, &Pin, &Pin)) -> Result<RGBLed> {
+        RGBLed::new_with_color((r_pin, g_pin, b_pin), (1.0, 1.0, 1.0))
     }
 
-    pub fn new_with_color((r_gpio, g_gpio, b_gpio) : (Gpio, Gpio, Gpio),
-        (r, g, b): (f32, f32, f32)) -> RGBLed {
-        RGBLed {
-            r_led: PwmLed::new_with_luminosity(r_gpio, r),
-            g_led: PwmLed::new_with_luminosity(g_gpio, g),
-            b_led: PwmLed::new_with_luminosity(b_gpio, b),
-        }
+    pub fn new_with_color(pins: (&Pin, &Pin, &Pin), color: (f32, f32, f32)) -> Result<RGBLed> {
+        RGBLed::new_with_sysfs(pins, color, Sysfs::default())
     }
 
-    pub fn color_code_to_luminosity(mut r: u32, mut g: u32, mut b: u32, mut a: u32) -> (f32, f32, f32) {
-        // TODO utils to check bound, same for pwmled
-        if r > 255 {
-            r = 255;
-        }
-        if g > 255 {
-            g = 255;
-        }
-        if b > 255 {
-            b = 255;
-        }
-        if a > 255 {
-            a = 255;
-        }
+    pub fn new_with_sysfs(pins: (&Pin, &Pin, &Pin), color: (f32, f32, f32), fs: Sysfs) -> Result<RGBLed> {
+        RGBLed::new_with_clock(pins, color, fs, system_clock())
+    }
+
+    pub fn new_with_clock((r_pin, g_pin, b_pin) : (&Pin, &Pin, &Pin),
+        (r, g, b): (f32, f32, f32), fs: Sysfs, clock: Arc<dyn Clock>) -> Result<RGBLed> {
+        Ok(RGBLed {
+            r_led: PwmLed::new_with_clock(r_pin, r, fs.clone(), clock.clone())?,
+            g_led: PwmLed::new_with_clock(g_pin, g, fs.clone(), clock.clone())?,
+            b_led: PwmLed::new_with_clock(b_pin, b, fs, clock.clone())?,
+            clock,
+        })
+    }
+
+    pub fn color_code_to_luminosity(r: u32, g: u32, b: u32, a: u32) -> (f32, f32, f32) {
+        let (r, g, b, a) = (r.min(255), g.min(255), b.min(255), a.min(255));
         ((r * a) as f32 / 255.0, (g * a) as f32 / 255.0, (b * a) as f32 / 255.0)
     }
+}
 
-    pub fn set_color(&mut self, (r, g, b): (f32, f32, f32)) -> bool {
-        let res = self.r_led.set_luminosity(r);
-        if !res {
-            return false;
+impl<P: PwmChannel> RGBLed<P> {
+    pub fn new_from_leds((r_led, g_led, b_led): (PwmLed<P>, PwmLed<P>, PwmLed<P>)) -> RGBLed<P> {
+        RGBLed {
+            r_led,
+            g_led,
+            b_led,
+            clock: system_clock(),
         }
-        let res = self.g_led.set_luminosity(g);
-        if !res {
-            return false;
+    }
+
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> RGBLed<P> {
+        RGBLed {
+            r_led: self.r_led.with_clock(clock.clone()),
+            g_led: self.g_led.with_clock(clock.clone()),
+            b_led: self.b_led.with_clock(clock.clone()),
+            clock,
         }
-        let res = self.b_led.set_luminosity(b);
-        res
     }
 
+    pub fn set_color(&mut self, (r, g, b): (f32, f32, f32)) -> Result<()> {
+        self.r_led.set_luminosity(r)?;
+        self.g_led.set_luminosity(g)?;
+        self.b_led.set_luminosity(b)
+    }
 
-    pub fn fade_to(&mut self, (mut r, mut g, mut b): (f32, f32, f32), duration_ms: u32, update_period_ms: u32) -> bool {
-        let mut step = duration_ms / update_period_ms;
-        if step == 0 {
-            step = 1;
-        }
-        if r > 1.0 {
-            r = 1.0;
-        } else if r < 0.0 {
-            r = 0.0;
-        }
-        let current_r = self.r_led.get_luminosity();
-        let inc_r = (r - current_r) / step as f32;
-        if g > 1.0 {
-            g = 1.0;
-        } else if g < 0.0 {
-            g = 0.0;
-        }
-        let current_g = self.g_led.get_luminosity();
-        let inc_g = (g - current_g) / step as f32;
-        if b > 1.0 {
-            b = 1.0;
-        } else if b < 0.0 {
-            b = 0.0;
-        }
-        let current_b = self.b_led.get_luminosity();
-        let inc_b = (b - current_b) / step as f32;
 
-        for _ in 0..step {
-            if !self.r_led.set_luminosity(self.r_led.get_luminosity() + inc_r) {
-                break;
-            }
-            if !self.g_led.set_luminosity(self.g_led.get_luminosity() + inc_g) {
-                break;
-            }
-            if !self.b_led.set_luminosity(self.b_led.get_luminosity() + inc_b) {
-                break;
-            }
-            std::thread::sleep(std::time::Duration::from_millis(update_period_ms as u64));
-        }
+    pub fn fade_to(&mut self, color: (f32, f32, f32), duration_ms: u32, update_period_ms: u32) -> Result<()> {
+        self.fade_to_with_easing(color, duration_ms, update_period_ms, Easing::Linear)
+    }
 
-        true
+    pub fn fade_to_with_easing(&mut self, (r, g, b): (f32, f32, f32), duration_ms: u32, update_period_ms: u32,
+        easing: Easing) -> Result<()> {
+        let color = (r.clamp(0.0, 1.0), g.clamp(0.0, 1.0), b.clamp(0.0, 1.0));
+        let current_color = (self.r_led.get_luminosity()?, self.g_led.get_luminosity()?, self.b_led.get_luminosity()?);
+        let clock = self.clock.clone();
+        run_transition(&*clock, Duration::from_millis(duration_ms as u64), Duration::from_millis(update_period_ms as u64),
+            easing, |progress| self.set_color(current_color.interpolate(color, progress)))
     }
 
 
-    pub fn blink(&mut self, proportion: f32, speed: u32) -> bool {
-        let res = self.r_led.blink(proportion, speed);
-        if !res {
-            return false;
-        }
-        let res = self.g_led.blink(proportion, speed);
-        if !res {
-            return false;
-        }
-        let res = self.b_led.blink(proportion, speed);
-        res
+    pub fn blink(&mut self, proportion: f32, speed: u32) -> Result<()> {
+        self.r_led.blink(proportion, speed)?;
+        self.g_led.blink(proportion, speed)?;
+        self.b_led.blink(proportion, speed)
     }
 
-}
\ No newline at end of file
+}
@@ -1,128 +1,345 @@
-
+use crate::clock::*;
+use crate::error::*;
+use crate::hal::*;
 use crate::maestro::*;
+use crate::motion::*;
 use crate::pin::*;
+use crate::sysfs::Sysfs;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-pub struct PwmServoSettings {
-    pub pwm: Pwm,
+/**
+ * Timings of a PWM servo
+ * frequency: refresh rate in Hz
+ * min_pulse_ns/max_pulse_ns: pulse width for 0 and degrees, min_pulse_ns
+ * must be below max_pulse_ns, use inverted for a servo turning the other way
+ * degrees: angular range of the servo
+ * center_trim_ns: offset added to every pulse to fix a mechanical offset
+ * inverted: if 0 is at max_pulse_ns
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServoProfile {
+    pub frequency: u32,
+    pub min_pulse_ns: u32,
+    pub max_pulse_ns: u32,
     pub degrees: f32,
-    pub period: u32,
-    pub min_duty: u32,
-    pub max_duty: u32,
+    pub center_trim_ns: i32,
+    pub inverted: bool,
+}
+
+impl Default for ServoProfile {
+    fn default() -> ServoProfile {
+        ServoProfile::GENERIC
+    }
 }
 
-pub struct MaestroServoSettings {
+impl ServoProfile {
+    /// Analog servo, 60 Hz, 0.5 ms to 2.5 ms
+    pub const GENERIC: ServoProfile = ServoProfile {
+        frequency: 60,
+        min_pulse_ns: 500000,
+        max_pulse_ns: 2500000,
+        degrees: 180.0,
+        center_trim_ns: 0,
+        inverted: false,
+    };
+
+    /// TowerPro SG90 micro servo, 50 Hz, 0.5 ms to 2.4 ms
+    pub const SG90: ServoProfile = ServoProfile {
+        frequency: 50,
+        min_pulse_ns: 500000,
+        max_pulse_ns: 2400000,
+        degrees: 180.0,
+        center_trim_ns: 0,
+        inverted: false,
+    };
+
+    /// TowerPro MG996R, 50 Hz, 0.5 ms to 2.5 ms
+    pub const MG996R: ServoProfile = ServoProfile {
+        frequency: 50,
+        min_pulse_ns: 500000,
+        max_pulse_ns: 2500000,
+        degrees: 180.0,
+        center_trim_ns: 0,
+        inverted: false,
+    };
+
+    /// Digital servo refreshed at 333 Hz, 0.9 ms to 2.1 ms
+    pub const DIGITAL_333HZ: ServoProfile = ServoProfile {
+        frequency: 333,
+        min_pulse_ns: 900000,
+        max_pulse_ns: 2100000,
+        degrees: 120.0,
+        center_trim_ns: 0,
+        inverted: false,
+    };
+
+    /**
+     * @return the period in ns
+     */
+    pub fn period_ns(&self) -> u32 {
+        (1000000000_f32 / self.frequency as f32) as u32
+    }
+
+    /**
+     * @return the pulse width range in ns, 0 if max_pulse_ns is not above
+     * min_pulse_ns
+     */
+    fn span_ns(&self) -> u32 {
+        self.max_pulse_ns.saturating_sub(self.min_pulse_ns)
+    }
+
+    /**
+     * Convert a position to a pulse width
+     * @param position      position between 0 and degrees
+     * @return the pulse width in ns
+     */
+    pub fn pulse_ns(&self, position: f32) -> u32 {
+        let mut ratio = position.clamp(0.0, self.degrees) / self.degrees;
+        if self.inverted {
+            ratio = 1.0 - ratio;
+        }
+        let pulse = self.min_pulse_ns as f32
+            + self.span_ns() as f32 * ratio
+            + self.center_trim_ns as f32;
+        pulse.clamp(0.0, self.period_ns() as f32) as u32
+    }
+
+    /**
+     * Convert a pulse width to a position
+     * @param pulse_ns      pulse width in ns
+     * @return the position between 0 and degrees, 0 if the profile has
+     * no pulse width range
+     */
+    pub fn position(&self, pulse_ns: u32) -> f32 {
+        let span_ns = self.span_ns();
+        if span_ns == 0 {
+            return 0.0;
+        }
+        let pulse = pulse_ns as f32 - self.center_trim_ns as f32 - self.min_pulse_ns as f32;
+        let mut ratio = (pulse / span_ns as f32).clamp(0.0, 1.0);
+        if self.inverted {
+            ratio = 1.0 - ratio;
+        }
+        ratio * self.degrees
+    }
+}
+
+/**
+ * A servo driven by a PWM output
+ */
+pub struct PwmServo<P: PwmChannel = Pwm> {
+    pub pwm: P,
+    pub profile: ServoProfile,
+}
+
+/**
+ * A servo plugged on a Maestro channel
+ */
+pub struct MaestroChannel {
     pub servo_range: f32,
     pub channel: u8,
     pub maestro: Arc<Mutex<Maestro>>
 }
 
-pub struct Servo {
-    pwm_settings: Option<PwmServoSettings>,
-    maestro_settings: Option<MaestroServoSettings>,
+impl<P: PwmChannel> ServoDriver for PwmServo<P> {
+    fn range(&self) -> f32 {
+        self.profile.degrees
+    }
+
+    fn set_position(&mut self, position: f32) -> Result<()> {
+        self.pwm.set_duty_ns(self.profile.pulse_ns(position))
+    }
+
+    fn position(&mut self) -> Result<f32> {
+        Ok(self.profile.position(self.pwm.get_duty_ns()?))
+    }
+
+    fn pulse_width_us(&mut self) -> Result<f32> {
+        Ok(self.pwm.get_duty_ns()? as f32 / 1000.0)
+    }
 }
 
-impl Servo {
+impl MaestroChannel {
+    /**
+     * Range of the channel, with the degrees of the servo
+     */
+    fn channel_range(&self, maestro: &Maestro) -> ChannelRange {
+        ChannelRange {
+            degrees: self.servo_range,
+            ..maestro.channel_range(self.channel)
+        }
+    }
+
+    fn position_to_target(&self, maestro: &Maestro, position: f32) -> u16 {
+        self.channel_range(maestro).to_target(position, TargetUnit::Degrees)
+    }
+
+    /**
+     * @return the position reported by the Maestro in quarter-µs
+     */
+    pub fn quarter_us(&mut self) -> Result<u16> {
+        self.maestro.lock().unwrap().get_target(self.channel)
+    }
+}
+
+impl ServoDriver for MaestroChannel {
+    fn range(&self) -> f32 {
+        self.servo_range
+    }
+
+    fn set_position(&mut self, position: f32) -> Result<()> {
+        let mut maestro = self.maestro.lock().unwrap();
+        let target = self.position_to_target(&maestro, position);
+        maestro.set_target(self.channel, target)
+    }
+
+    fn position(&mut self) -> Result<f32> {
+        let mut maestro = self.maestro.lock().unwrap();
+        let pos = maestro.get_target(self.channel)?;
+        Ok(self.channel_range(&maestro).from_target(pos, TargetUnit::Degrees))
+    }
+
+    fn pulse_width_us(&mut self) -> Result<f32> {
+        Ok(self.quarter_us()? as f32 / 4.0)
+    }
+}
+
+pub struct Servo<D: ServoDriver = PwmServo> {
+    pub driver: D,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Servo<PwmServo> {
     /**
      * Create a new PWM servo
      */
-    pub fn new(gpio: Gpio, degrees: f32) -> Servo {
-        Servo::new_with_position(gpio, degrees, 0.0)
+    pub fn new(pin: &Pin, degrees: f32) -> Result<Servo> {
+        Servo::new_with_position(pin, degrees, 0.0)
     }
 
     /**
      * Create a new PWM servo with a given position
      */
-    pub fn new_with_position(gpio: Gpio, degrees: f32, position: f32) -> Servo {
-        // TODO, do we want to configure this?
-        let frequency = 60; // Hz
-        let period: u32 = ((1.0 / frequency as f32) * 1000000000 as f32 /* ns */) as u32;
-        let min_duty = 500000 /* 0.5 ms */;
-        let max_duty = 2500000 /* 2.5 ms */;
-
-        let mut position = position;
-        if position > degrees {
-            position = degrees;
-        } else if position < 0.0 {
-            position = 0.0;
-        }
-        let duty = min_duty as f32 + (max_duty - min_duty) as f32 * (position / degrees);
-        let pwm = gpio_to_pwm(&gpio);
-        if pwm.is_none() {
-            panic!("Invalid PWM");
-        }
-        let mut pwm = pwm.unwrap();
-        pwm.start_pwm(duty as u32, period);
+    pub fn new_with_position(pin: &Pin, degrees: f32, position: f32) -> Result<Servo> {
+        let profile = ServoProfile {
+            degrees,
+            ..ServoProfile::GENERIC
+        };
+        Servo::new_with_profile(pin, profile, position)
+    }
+
+    /**
+     * Create a new PWM servo with the timings of a given servo model
+     */
+    pub fn new_with_profile(pin: &Pin, profile: ServoProfile, position: f32) -> Result<Servo> {
+        Servo::new_with_sysfs(pin, profile, position, Sysfs::default())
+    }
+
+    /**
+     * Create a new PWM servo in a given sysfs tree
+     */
+    pub fn new_with_sysfs(pin: &Pin, profile: ServoProfile, position: f32, fs: Sysfs) -> Result<Servo> {
+        Servo::new_with_clock(pin, profile, position, fs, system_clock())
+    }
+
+    /**
+     * Create a new PWM servo in a given sysfs tree, waiting on a given clock
+     */
+    pub fn new_with_clock(pin: &Pin, profile: ServoProfile, position: f32, fs: Sysfs,
+        clock: Arc<dyn Clock>) -> Result<Servo> {
+        let pwm = pin_to_pwm_with_clock(pin, fs, clock.clone())
+            .ok_or_else(|| Error::InvalidPwmPin(pin.name.to_string()))?;
+        Ok(Servo::new_from_pwm(pwm, profile, position)?.with_clock(clock))
+    }
+}
+
+impl<P: PwmChannel> Servo<PwmServo<P>> {
+    /**
+     * Create a new servo on any PWM output with a given position
+     */
+    pub fn new_from_pwm(mut pwm: P, profile: ServoProfile, position: f32) -> Result<Servo<PwmServo<P>>> {
+        pwm.start_pwm(profile.pulse_ns(position), profile.period_ns())?;
+        Ok(Servo::new_from_driver(PwmServo {
+            pwm,
+            profile
+        }))
+    }
+}
+
+impl Servo<MaestroChannel> {
+    /**
+     * Create a new Servo linked to a Maestro board
+     */
+    pub fn new_from_maestro(servo_range: f32, channel: u8, maestro: Arc<Mutex<Maestro>>) -> Servo<MaestroChannel> {
+        Servo::new_from_driver(MaestroChannel {
+            servo_range,
+            channel,
+            maestro
+        })
+    }
+
+    /**
+     * @return the position reported by the Maestro in quarter-µs
+     */
+    pub fn quarter_us(&mut self) -> Result<u16> {
+        self.driver.quarter_us()
+    }
+
+    /**
+     * Move the servo to the home position of its channel
+     * @return if the operation was successful
+     */
+    pub fn return_home(&mut self) -> Result<()> {
+        self.driver.maestro.lock().unwrap().return_home(self.driver.channel)
+    }
+}
+
+impl<D: ServoDriver> Servo<D> {
+    /**
+     * Create a new Servo on any driver
+     */
+    pub fn new_from_driver(driver: D) -> Servo<D> {
         Servo {
-            pwm_settings: Some(PwmServoSettings {
-                pwm,
-                degrees,
-                period,
-                min_duty,
-                max_duty
-            }),
-            maestro_settings: None
+            driver,
+            clock: system_clock(),
         }
     }
 
     /**
-     * Create a new Servo linked to a Maestro board
+     * Use another clock to wait during transitions
      */
-    pub fn new_from_maestro(servo_range: f32, channel: u8, maestro: Arc<Mutex<Maestro>>) -> Servo {
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Servo<D> {
         Servo {
-            pwm_settings: None,
-            maestro_settings: Some(MaestroServoSettings {
-                servo_range,
-                channel,
-                maestro
-            })
+            clock,
+            ..self
         }
     }
 
-     /**
+    /**
      * Change a servo position
      * @param position     wanted position in servo_range
      * @return if the operation was successful
      */
-    pub fn set_position(&mut self, mut position: f32) -> bool {
-        if self.pwm_settings.is_some() {
-            let settings = self.pwm_settings.as_mut().unwrap();
-            if position > settings.degrees {
-                position = settings.degrees;
-            } else if position < 0.0 {
-                position = 0.0;
-            }
-            let duty_cycle = settings.min_duty as f32 + ((settings.max_duty - settings.min_duty) as f32 * (position / settings.degrees));
-            return settings.pwm.set_duty_ns(duty_cycle as u32);
-        }
-        let settings = self.maestro_settings.as_ref().unwrap();
-        let mut position = position;
-        if position > settings.servo_range {
-            position = settings.servo_range;
-        }
-        let pos_to_target = position as f32 / settings.servo_range;
-        let mut maestro = settings.maestro.lock().unwrap();
-        let target = maestro.min_target + ((maestro.max_target - maestro.min_target) as f32 * pos_to_target) as u16;
-        maestro.set_target(settings.channel, target)
+    pub fn set_position(&mut self, position: f32) -> Result<()> {
+        let position = position.clamp(0.0, self.driver.range());
+        self.driver.set_position(position)
     }
 
     /**
      * Get current position returned by the beagle
      * @note a difference will exists between real value and what the Maestro send
-     * @return the position of the servo
+     * @return the position of the servo in degrees
      */
-    pub fn get_position(&mut self) -> u16 {
-        if self.maestro_settings.is_none() {
-            let settings = self.pwm_settings.as_ref().unwrap();
-            let current_cycle: f32 = settings.pwm.get_duty_ns() as f32;
-            let current_pos = current_cycle / (settings.max_duty - settings.min_duty) as f32;
-            return current_pos as u16;
-        }
-        let settings = self.maestro_settings.as_ref().unwrap();
-        let mut maestro = settings.maestro.lock().unwrap();
-        let mut pos = maestro.get_target(settings.channel);
-        pos = (((pos as f32  - maestro.min_target as f32) / (maestro.max_target as f32 - maestro.min_target as f32)) * settings.servo_range) as u16;
-        pos
+    pub fn position(&mut self) -> Result<f32> {
+        self.driver.position()
+    }
+
+    /**
+     * @return the pulse width currently sent to the servo in µs
+     */
+    pub fn pulse_width_us(&mut self) -> Result<f32> {
+        self.driver.pulse_width_us()
     }
 
     /**
@@ -132,62 +349,263 @@ impl Servo {
      * @param update_period_ms  Period beetween steps
      * @return if the operation was successful
      */
-    pub fn go_to(&mut self, mut position: f32, duration_ms: u32, update_period_ms: u32) -> bool {
-        let mut step = duration_ms / update_period_ms;
-        if step == 0 {
-            step = 1;
-        }
-        if self.pwm_settings.is_some() {
-            let settings = self.pwm_settings.as_ref().unwrap();
-            if position > settings.degrees {
-                position = settings.degrees;
-            } else if position < 0.0 {
-                position = 0.0;
-            }
-        } else {
-            let settings = self.maestro_settings.as_ref().unwrap();
-            if position > settings.servo_range as f32 {
-                position = settings.servo_range as f32;
-            } else if position < 0.0 {
-                position = 0.0;
-            }
+    pub fn go_to(&mut self, position: f32, duration_ms: u32, update_period_ms: u32) -> Result<()> {
+        self.go_to_with_easing(position, duration_ms, update_period_ms, Easing::Linear)
+    }
+
+    /**
+     * Go to a given position in the duration given, following an easing curve
+     * @param position          Wanted position
+     * @param duration_ms       Wanted time for the transition
+     * @param update_period_ms  Period beetween steps
+     * @param easing            Progress curve
+     * @return if the operation was successful
+     */
+    pub fn go_to_with_easing(&mut self, position: f32, duration_ms: u32, update_period_ms: u32,
+        easing: Easing) -> Result<()> {
+        let position = position.clamp(0.0, self.driver.range());
+        let current_position = self.position()?;
+        let clock = self.clock.clone();
+        run_transition(&*clock, Duration::from_millis(duration_ms as u64), Duration::from_millis(update_period_ms as u64),
+            easing, |progress| self.set_position(current_position.interpolate(position, progress)))
+    }
+}
+
+/**
+ * Servos plugged on the same Maestro, moved together. Consecutive channels
+ * are sent in one Set Multiple Targets packet, so they start in the same frame.
+ */
+pub struct ServoGroup {
+    maestro: Arc<Mutex<Maestro>>,
+    servos: Vec<Servo<MaestroChannel>>,
+}
+
+impl ServoGroup {
+    /**
+     * Create a new group
+     * @param servos        servos of the group, all on the same Maestro, one per channel
+     * @return the new group
+     */
+    pub fn new(servos: Vec<Servo<MaestroChannel>>) -> Result<ServoGroup> {
+        let maestro = match servos.first() {
+            Some(servo) => servo.driver.maestro.clone(),
+            None => return Err(Error::InvalidServoGroup(String::from("no servo in the group"))),
+        };
+        if servos.iter().any(|servo| !Arc::ptr_eq(&servo.driver.maestro, &maestro)) {
+            return Err(Error::InvalidServoGroup(String::from("servos are on different Maestros")));
+        }
+        let mut channels: Vec<u8> = servos.iter().map(|servo| servo.driver.channel).collect();
+        channels.sort_unstable();
+        if let Some(pair) = channels.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(Error::InvalidServoGroup(format!("several servos on channel {}", pair[0])));
         }
+        Ok(ServoGroup {
+            maestro,
+            servos
+        })
+    }
 
-        let inc;
-        let current_position;
-        if self.pwm_settings.is_some() {
-            let settings = self.pwm_settings.as_ref().unwrap();
-            let current_cycle: f32 = settings.pwm.get_duty_ns() as f32;
-            let wanted_cycle: f32 = settings.min_duty as f32 + ((settings.max_duty - settings.min_duty) as f32 * (position / settings.degrees));
-            inc = (wanted_cycle - current_cycle) / step as f32;
-            current_position = current_cycle / (settings.max_duty - settings.min_duty) as f32;
-        } else {
-            current_position = self.get_position() as f32;
-            let wanted_postion = position as f32;
-            inc = (wanted_postion - current_position) / step as f32;
-        }
-
-        for s in 0..step {
-            if self.pwm_settings.is_some() {
-                let settings = self.pwm_settings.as_mut().unwrap();
-                let mut new_cycle = settings.pwm.get_duty_ns() as i32 + inc as i32;
-                if new_cycle < 0 {
-                    new_cycle = 0;
-                }
-                let new_cycle = new_cycle as u32;
-                if !settings.pwm.set_duty_ns(new_cycle) {
-                    return false;
-                }
-            } else {
-                let new_pos = current_position + inc * s as f32;
-                if !self.set_position(new_pos) {
-                    return false;
+    /**
+     * @return the servos of the group
+     */
+    pub fn servos(&mut self) -> &mut [Servo<MaestroChannel>] {
+        &mut self.servos
+    }
+
+    /**
+     * @return the servos of the group
+     */
+    pub fn into_servos(self) -> Vec<Servo<MaestroChannel>> {
+        self.servos
+    }
+
+    /**
+     * Move all servos of the group
+     * @param positions     wanted position for each servo, in the group order
+     * @return if the operation was successful
+     */
+    pub fn set_positions(&mut self, positions: &[f32]) -> Result<()> {
+        if positions.len() != self.servos.len() {
+            return Err(Error::InvalidServoGroup(format!("{} positions for {} servos",
+                positions.len(), self.servos.len())));
+        }
+        let mut maestro = self.maestro.lock().unwrap();
+        let mut targets: Vec<(u8, u16)> = self.servos.iter().zip(positions)
+            .map(|(servo, &position)| (servo.driver.channel, servo.driver.position_to_target(&maestro, position)))
+            .collect();
+        targets.sort_by_key(|(channel, _)| *channel);
+
+        // Send one packet per run of consecutive channels
+        let mut first = 0;
+        for i in 1..=targets.len() {
+            if i == targets.len() || targets[i].0 != targets[i - 1].0 + 1 {
+                let run: Vec<u16> = targets[first..i].iter().map(|(_, target)| *target).collect();
+                match run.len() {
+                    1 => maestro.set_target(targets[first].0, run[0])?,
+                    _ => maestro.set_targets(targets[first].0, &run)?,
                 }
+                first = i;
             }
-            std::thread::sleep(std::time::Duration::from_millis(update_period_ms as u64));
         }
+        Ok(())
+    }
+
+    /**
+     * Move all servos of the group to the same position
+     * @param position      wanted position
+     * @return if the operation was successful
+     */
+    pub fn set_position(&mut self, position: f32) -> Result<()> {
+        let positions = vec![position; self.servos.len()];
+        self.set_positions(&positions)
+    }
+
+    /**
+     * @return the position of each servo in degrees, in the group order
+     */
+    pub fn positions(&mut self) -> Result<Vec<f32>> {
+        self.servos.iter_mut().map(|servo| servo.position()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * PWM output keeping each duty cycle with the time it was set
+     */
+    struct RecordingPwm {
+        clock: ManualClock,
+        period_ns: u32,
+        duty_ns: u32,
+        duties: Arc<Mutex<Vec<(Duration, u32)>>>,
+    }
+
+    impl PwmChannel for RecordingPwm {
+        fn get_period_ns(&self) -> Result<u32> {
+            Ok(self.period_ns)
+        }
+
+        fn get_duty_ns(&self) -> Result<u32> {
+            Ok(self.duty_ns)
+        }
+
+        fn set_period_ns(&mut self, period_ns: u32) -> Result<()> {
+            self.period_ns = period_ns;
+            Ok(())
+        }
+
+        fn set_duty_ns(&mut self, duty_ns: u32) -> Result<()> {
+            self.duty_ns = duty_ns;
+            self.duties.lock().unwrap().push((self.clock.now(), duty_ns));
+            Ok(())
+        }
+
+        fn start_pwm(&mut self, duty_ns: u32, period_ns: u32) -> Result<()> {
+            self.period_ns = period_ns;
+            self.set_duty_ns(duty_ns)
+        }
+    }
 
-        true
+    #[test]
+    fn go_to_lands_on_target_at_duration() {
+        let clock = ManualClock::new();
+        let duties = Arc::new(Mutex::new(Vec::new()));
+        let pwm = RecordingPwm {
+            clock: clock.clone(),
+            period_ns: 0,
+            duty_ns: 0,
+            duties: duties.clone(),
+        };
+        let mut servo = Servo::new_from_pwm(pwm, ServoProfile::SG90, 0.0).unwrap().with_clock(Arc::new(clock.clone()));
+        servo.go_to(180.0, 5000, 1000).unwrap();
+
+        assert_eq!(clock.now(), Duration::from_secs(5));
+        assert_eq!(*duties.lock().unwrap(), [
+            (Duration::from_secs(0), 500000),
+            (Duration::from_secs(1), 880000),
+            (Duration::from_secs(2), 1260000),
+            (Duration::from_secs(3), 1640000),
+            (Duration::from_secs(4), 2020000),
+            (Duration::from_secs(5), 2400000),
+        ]);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn profile_converts_positions_both_ways() {
+        let profile = ServoProfile::SG90;
+        assert_eq!(profile.period_ns(), 20000000);
+        assert_eq!((profile.pulse_ns(0.0), profile.pulse_ns(90.0), profile.pulse_ns(180.0)), (500000, 1450000, 2400000));
+        for position in [0.0, 45.0, 90.0, 135.0, 180.0].iter() {
+            assert!((profile.position(profile.pulse_ns(*position)) - position).abs() < 0.01);
+        }
+        // Out of range values stay on the servo range
+        assert_eq!((profile.pulse_ns(-10.0), profile.pulse_ns(200.0)), (500000, 2400000));
+        assert_eq!((profile.position(0), profile.position(3000000)), (0.0, 180.0));
+
+        let inverted = ServoProfile { inverted: true, ..profile };
+        assert_eq!((inverted.pulse_ns(0.0), inverted.pulse_ns(180.0)), (2400000, 500000));
+        assert!((inverted.position(inverted.pulse_ns(45.0)) - 45.0).abs() < 0.01);
+
+        let trimmed = ServoProfile { center_trim_ns: -20000, ..profile };
+        assert_eq!((trimmed.pulse_ns(0.0), trimmed.pulse_ns(90.0)), (480000, 1430000));
+        assert!((trimmed.position(1430000) - 90.0).abs() < 0.01);
+
+        // Reversed limits don't wrap around, they are a fixed pulse
+        let reversed = ServoProfile { min_pulse_ns: 2400000, max_pulse_ns: 500000, ..profile };
+        assert_eq!((reversed.pulse_ns(0.0), reversed.pulse_ns(180.0)), (2400000, 2400000));
+        assert_eq!(reversed.position(1450000), 0.0);
+        let fixed = ServoProfile { max_pulse_ns: 500000, ..profile };
+        assert_eq!(fixed.pulse_ns(90.0), 500000);
+        assert_eq!((fixed.position(400000), fixed.position(500000), fixed.position(600000)), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn pulse_stays_in_the_period() {
+        let fast = ServoProfile { frequency: 1000, max_pulse_ns: 2500000, ..ServoProfile::GENERIC };
+        assert_eq!(fast.period_ns(), 1000000);
+        assert_eq!(fast.pulse_ns(180.0), 1000000);
+        let trimmed = ServoProfile { center_trim_ns: -1000000, ..ServoProfile::GENERIC };
+        assert_eq!(trimmed.pulse_ns(0.0), 0);
+    }
+
+    fn group(channels: &[u8]) -> (Result<ServoGroup>, Arc<Mutex<Vec<u8>>>) {
+        let (maestro, written) = crate::maestro::tests::recording_maestro(MaestroProtocol::Pololu);
+        let maestro = Arc::new(Mutex::new(maestro));
+        let servos = channels.iter().map(|&channel| Servo::new_from_maestro(180.0, channel, maestro.clone())).collect();
+        (ServoGroup::new(servos), written)
+    }
+
+    #[test]
+    fn group_rejects_shared_channels() {
+        assert!(matches!(group(&[0, 3, 1, 3]).0, Err(Error::InvalidServoGroup(_))));
+        assert!(matches!(group(&[]).0, Err(Error::InvalidServoGroup(_))));
+    }
+
+    #[test]
+    fn group_sends_one_packet_per_run() {
+        let (group, written) = group(&[4, 1, 0, 2, 6]);
+        let mut group = group.unwrap();
+        let positions = [10.0, 20.0, 30.0, 40.0, 50.0];
+        group.set_positions(&positions).unwrap();
+
+        let (mut expected, expected_written) = crate::maestro::tests::recording_maestro(MaestroProtocol::Pololu);
+        let targets: Vec<u16> = group.servos().iter().zip(positions.iter())
+            .map(|(servo, &position)| servo.driver.position_to_target(&expected, position))
+            .collect();
+        // Channels 0, 1, 2 at once, then 4 and 6 alone
+        expected.set_targets(0, &[targets[2], targets[1], targets[3]]).unwrap();
+        expected.set_target(4, targets[0]).unwrap();
+        expected.set_target(6, targets[4]).unwrap();
+        assert_eq!(*written.lock().unwrap(), *expected_written.lock().unwrap());
+        let written = written.lock().unwrap();
+        // 0xAA, device, command, then the data and the CRC
+        assert_eq!(written.len(), 12 + 7 + 7);
+        assert_eq!((written[2], written[3], written[4]), (0x1f, 3, 0));
+        assert_eq!((written[14], written[15]), (0x04, 4));
+        assert_eq!((written[21], written[22]), (0x04, 6));
+
+        assert!(group.set_positions(&positions[1..]).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use crate::error::*;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/**
+ * Represent the sysfs tree used by the drivers. Every attribute path is
+ * resolved against root, which is "/" on the board and can be moved to a
+ * temporary directory to run the drivers against a fake tree.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sysfs {
+    pub root: PathBuf,
+}
+
+impl Default for Sysfs {
+    fn default() -> Sysfs {
+        Sysfs::new("/")
+    }
+}
+
+impl Sysfs {
+    /**
+     * Get a new sysfs tree
+     * @param root      Directory containing the sys/ tree
+     * @return the new Sysfs instance
+     */
+    pub fn new<P: Into<PathBuf>>(root: P) -> Sysfs {
+        Sysfs {
+            root: root.into()
+        }
+    }
+
+    /**
+     * Resolve an absolute sysfs path against root
+     * @param path      Path as seen on the board (e.g. /sys/class/pwm)
+     * @return the real path
+     */
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    /**
+     * @param path      Path as seen on the board
+     * @return if the attribute or directory exists
+     */
+    pub fn exists(&self, path: &str) -> bool {
+        self.path(path).exists()
+    }
+
+    /**
+     * List a directory
+     * @param path      Path as seen on the board
+     * @return the sorted names of the entries
+     */
+    pub fn list(&self, path: &str) -> Result<Vec<String>> {
+        let real_path = self.path(path);
+        let entries = fs::read_dir(&real_path).map_err(|err| Error::sysfs(real_path.to_string_lossy(), err))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|err| Error::sysfs(real_path.to_string_lossy(), err))?;
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /**
+     * Read an attribute
+     * @param path      Path as seen on the board
+     * @return the trimmed content of the attribute
+     */
+    pub fn read(&self, path: &str) -> Result<String> {
+        let real_path = self.path(path);
+        fs::read_to_string(&real_path)
+            .map(|value| value.trim().to_string())
+            .map_err(|err| Error::sysfs(real_path.to_string_lossy(), err))
+    }
+
+    /**
+     * Read a numeric attribute
+     * @param path      Path as seen on the board
+     * @return the parsed value
+     */
+    pub fn read_u32(&self, path: &str) -> Result<u32> {
+        let value = self.read(path)?;
+        value.parse::<u32>().map_err(|_| Error::sysfs(self.path(path).to_string_lossy(),
+            io::Error::new(io::ErrorKind::InvalidData, format!("invalid value: {}", value))))
+    }
+
+    /**
+     * Write an attribute
+     * @param path      Path as seen on the board
+     * @param value     Value to write
+     * @return if the operation was successful
+     */
+    pub fn write(&self, path: &str, value: &str) -> Result<()> {
+        let real_path = self.path(path);
+        fs::write(&real_path, value).map_err(|err| Error::sysfs(real_path.to_string_lossy(), err))
+    }
+}
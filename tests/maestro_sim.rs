@@ -0,0 +1,93 @@
+extern crate cucaracha;
+
+use cucaracha::maestro::*;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/**
+ * maestro-sim running in the background, killed when dropped
+ */
+struct Simulator {
+    child: Child,
+    link: String,
+}
+
+impl Simulator {
+    /**
+     * Start the simulator and wait until its link is ready
+     * @param name      name of the link, unique per test
+     * @param args      extra arguments
+     * @return the running simulator
+     */
+    fn start(name: &str, args: &[&str]) -> Simulator {
+        let link = std::env::temp_dir().join(format!("cucaracha-{}-{}", name, std::process::id()));
+        let link = link.to_string_lossy().into_owned();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_maestro-sim"))
+            .args(args)
+            .args(["--link", &link])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // The link is printed once the PTY is open
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+        assert_eq!(line.trim_end(), link);
+        Simulator { child, link }
+    }
+
+    fn settings(&self) -> MaestroSettings {
+        MaestroSettings {
+            port: self.link.clone(),
+            ..MaestroSettings::default()
+        }
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_file(&self.link);
+    }
+}
+
+#[test]
+fn maestro_drives_the_simulator() {
+    let simulator = Simulator::start("sim", &["--channels", "6"]);
+    let mut maestro = Maestro::new_with_settings(simulator.settings()).unwrap();
+    assert!(maestro.get_errors().unwrap().is_empty());
+    assert_eq!(maestro.get_target(0).unwrap(), 6000);
+
+    maestro.set_target(0, 7000).unwrap();
+    maestro.wait_until_settled(&[0], Duration::from_secs(1)).unwrap();
+    assert_eq!(maestro.get_target(0).unwrap(), 7000);
+    assert!(!maestro.is_moving().unwrap());
+
+    // 2000 quarter-µs at 10 per frame take 2 s
+    maestro.set_speed(1, 10).unwrap();
+    maestro.set_target(1, 8000).unwrap();
+    assert!(maestro.is_moving().unwrap());
+    assert!(maestro.is_channel_moving(1).unwrap());
+}
+
+#[test]
+fn chained_simulators_are_found() {
+    let simulator = Simulator::start("chain", &["--device", "12", "--device", "13", "--device", "127"]);
+    let maestro = Maestro::new_with_settings(simulator.settings()).unwrap();
+    let probe = MaestroSettings {
+        timeout: Duration::from_millis(20),
+        ..simulator.settings()
+    };
+    assert_eq!(maestro.bus().scan(&probe).unwrap(), [12, 13, 127]);
+}
+
+#[test]
+fn device_numbers_have_7_bits() {
+    let status = Command::new(env!("CARGO_BIN_EXE_maestro-sim"))
+        .args(["--device", "128"])
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}